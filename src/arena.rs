@@ -0,0 +1,217 @@
+// Memory that a process and the children it forks all see, for the locks and buffers they share. It's
+// one MAP_SHARED mapping that things get placed in one after the other and never taken out of again.
+//
+// The mapping is at the same address in every process forked after it was made, so references into it
+// stay good across fork. Where the next thing goes is kept in the mapping too, so it doesn't matter
+// which process places it. Every process unmaps its own view when it drops the arena, and the memory
+// goes away with the last one.
+//
+// What gets placed is borrowed from the arena rather than being &'static, even though it's never
+// taken out again. Dropping the arena unmaps the memory, and a 'static reference would outlive that.
+// Children forked while the arena is around get their own copy of it, so the borrow lasts as long
+// there as it does here.
+
+use libc::c_void;
+use std::cell::UnsafeCell;
+use std::io::{Error, Result};
+use std::mem::{align_of, size_of, MaybeUninit};
+use std::ptr;
+use std::sync::atomic::{
+    AtomicBool, AtomicI32, AtomicI64, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+};
+
+/// Types that work the same from every process that has the memory they're in
+///
+/// Nothing that's placed in an arena gets dropped, since no process knows whether it's the last one
+/// using it. Being shareable means there's nothing for drop to free anyway.
+///
+/// # Safety
+///
+/// Whatever process looks at the type has to get the same meaning out of it. A pointer is fine if it
+/// points into the same mapping, which is at the same address in every process forked after it was
+/// made. So is something that only means anything in one process, like a pointer into its heap or
+/// stack, or a thread id from gettid, as long as only that process ever interprets it, the way only
+/// the owner of a [`RobustMutex`](crate::robust::RobustMutex) follows its link. Anything else, like
+/// a pointer into the heap that other processes follow, won't work.
+pub unsafe trait ProcessShared {}
+
+macro_rules! process_shared {
+    ($($ty:ty),*) => {
+        $(unsafe impl ProcessShared for $ty {})*
+    };
+}
+
+process_shared!(
+    u8,
+    u16,
+    u32,
+    u64,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    isize,
+    f32,
+    f64,
+    bool,
+    char,
+    ()
+);
+process_shared!(
+    AtomicBool,
+    AtomicU8,
+    AtomicU32,
+    AtomicI32,
+    AtomicU64,
+    AtomicI64,
+    AtomicUsize
+);
+
+unsafe impl<T: ProcessShared, const N: usize> ProcessShared for [T; N] {}
+unsafe impl<T: ProcessShared> ProcessShared for MaybeUninit<T> {}
+unsafe impl<T: ProcessShared> ProcessShared for UnsafeCell<T> {}
+unsafe impl<T: ProcessShared> ProcessShared for Option<T> {}
+
+pub struct SharedArena {
+    base: *mut c_void,
+    len: usize,
+}
+
+// Placing is a single atomic update of the offset in the mapping, so any thread can do it
+unsafe impl Send for SharedArena {}
+unsafe impl Sync for SharedArena {}
+
+impl SharedArena {
+    // Map `size` bytes, some of which go to keeping track of what's been placed
+    pub fn new(size: usize) -> Result<Self> {
+        let len = size.max(size_of::<AtomicUsize>());
+        unsafe {
+            let base = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if base == libc::MAP_FAILED {
+                return Err(Error::last_os_error());
+            }
+
+            // The offset of the first free byte, right after itself
+            ptr::write(
+                base as *mut AtomicUsize,
+                AtomicUsize::new(size_of::<AtomicUsize>()),
+            );
+            Ok(SharedArena { base, len })
+        }
+    }
+
+    fn next(&self) -> &AtomicUsize {
+        unsafe { &*(self.base as *const AtomicUsize) }
+    }
+
+    // Move the value into the arena, or hand it back if it doesn't fit
+    pub fn try_alloc<T: ProcessShared>(&self, value: T) -> std::result::Result<&T, T> {
+        // The mapping starts on a page, so offsets aligned for T are addresses aligned for T
+        let place = |next: usize| next.next_multiple_of(align_of::<T>());
+        let Ok(next) = self
+            .next()
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                let end = place(next) + size_of::<T>();
+                (end <= self.len).then_some(end)
+            })
+        else {
+            return Err(value);
+        };
+
+        unsafe {
+            let location = self.base.add(place(next)) as *mut T;
+            ptr::write(location, value);
+            Ok(&*location)
+        }
+    }
+
+    pub fn alloc<T: ProcessShared>(&self, value: T) -> &T {
+        self.try_alloc(value).unwrap_or_else(|_| {
+            panic!(
+                "no room for {} bytes in an arena of {}",
+                size_of::<T>(),
+                self.len
+            )
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    // What's left, not counting any padding the next thing might need
+    pub fn remaining(&self) -> usize {
+        self.len - self.next().load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for SharedArena {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base, self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SharedArena;
+    use std::mem::align_of;
+    use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+    #[test]
+    fn places_are_aligned_and_separate() {
+        let arena = SharedArena::new(4096).unwrap();
+        let small = arena.alloc(1u8);
+        let big = arena.alloc(AtomicU64::new(2));
+        let array = arena.alloc([3u32; 4]);
+
+        assert_eq!(
+            big as *const AtomicU64 as usize % align_of::<AtomicU64>(),
+            0
+        );
+        assert_eq!(array as *const [u32; 4] as usize % align_of::<u32>(), 0);
+        assert_eq!(
+            (*small, big.load(Ordering::Relaxed), *array),
+            (1, 2, [3; 4])
+        );
+    }
+
+    #[test]
+    fn full_arena_hands_the_value_back() {
+        let arena = SharedArena::new(64).unwrap();
+        assert!(arena.try_alloc([0u8; 32]).is_ok());
+        assert_eq!(arena.try_alloc([7u8; 32]), Err([7u8; 32]));
+        assert!(arena.try_alloc(0u8).is_ok());
+    }
+
+    #[test]
+    fn children_see_the_same_memory() {
+        let arena = SharedArena::new(4096).unwrap();
+        let counter = arena.alloc(AtomicU32::new(0));
+        let remaining = arena.remaining();
+
+        let pid = unsafe { libc::fork() };
+        assert_ne!(pid, -1);
+        if pid == 0 {
+            counter.store(5, Ordering::SeqCst);
+
+            // Places taken in the child are gone for the parent too
+            arena.alloc(0u64);
+            unsafe { libc::_exit(0) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+
+        assert_eq!(counter.load(Ordering::SeqCst), 5);
+        assert!(arena.remaining() <= remaining - 8);
+    }
+}
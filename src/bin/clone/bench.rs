@@ -0,0 +1,193 @@
+// How long it takes to start a program with the different ways of creating a process. Every child
+// runs /bin/true, so what differs is the cost of creating it and getting it to exec. fork has to
+// copy our page tables, which gets expensive with a big heap, while the others share our memory until
+// the exec.
+
+use crate::error_exit;
+use advent_2::clone3::{clone3, CloneArgs};
+use advent_2::stack::Stack;
+use libc::{c_char, c_int, c_void, pid_t};
+use std::ffi::CString;
+use std::io::Error;
+use std::process;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_COUNT: usize = 200;
+
+const PROGRAM: &str = "/bin/true";
+
+extern "C" {
+    static environ: *const *const c_char;
+}
+
+// What the children need to exec, prepared up front so they don't have to allocate
+struct Exec {
+    path: CString,
+    argv: [*const c_char; 2],
+}
+
+impl Exec {
+    fn new() -> Self {
+        let path = CString::new(PROGRAM).unwrap();
+        let argv = [path.as_ptr(), ptr::null()];
+        Exec { path, argv }
+    }
+
+    // For the children: exec, or exit as if the shell couldn't find the program
+    unsafe fn exec(&self) -> ! {
+        libc::execv(self.path.as_ptr(), self.argv.as_ptr());
+        libc::_exit(127);
+    }
+}
+
+extern "C" fn exec_cb(arg: *mut c_void) -> c_int {
+    unsafe { (*(arg as *const Exec)).exec() }
+}
+
+#[derive(Clone, Copy)]
+enum Method {
+    Fork,
+    Vfork,
+    CloneVfork,
+    Clone3,
+    PosixSpawn,
+}
+
+const METHODS: [(Method, &str); 5] = [
+    (Method::Fork, "fork"),
+    (Method::Vfork, "vfork"),
+    (Method::CloneVfork, "clone(VM|VFORK)"),
+    (Method::Clone3, "clone3"),
+    (Method::PosixSpawn, "posix_spawn"),
+];
+
+// Start one child running the program and wait for it. `name` is the method's, for errors.
+unsafe fn spawn_and_reap(method: Method, name: &str, exec: &Exec, stack: &Stack) {
+    let pid: pid_t = match method {
+        Method::Fork => match libc::fork() {
+            0 => exec.exec(),
+            pid => pid,
+        },
+
+        // The parent is suspended until the child has exec'd or exited, and the child runs on our
+        // stack in the meantime, so it can't do anything but that. libc deprecates vfork because the
+        // compiler doesn't know it returns twice and may keep things in stack slots the child
+        // overwrites. Going straight to exec doesn't give it the chance.
+        #[allow(deprecated)]
+        Method::Vfork => match libc::vfork() {
+            0 => exec.exec(),
+            pid => pid,
+        },
+
+        // What vfork does under the hood, with a stack of the child's own
+        Method::CloneVfork => libc::clone(
+            exec_cb,
+            stack.top(),
+            libc::CLONE_VM | libc::CLONE_VFORK | libc::SIGCHLD,
+            exec as *const Exec as *mut c_void,
+        ),
+
+        Method::Clone3 => {
+            let args = CloneArgs {
+                exit_signal: libc::SIGCHLD as u64,
+                ..Default::default()
+            };
+            match clone3(&args) {
+                Ok(0) => exec.exec(),
+                Ok(pid) => pid,
+                Err(err) => fail("clone3", err),
+            }
+        }
+
+        Method::PosixSpawn => {
+            let mut pid: pid_t = 0;
+            let ret = libc::posix_spawn(
+                &mut pid,
+                exec.path.as_ptr(),
+                ptr::null(),
+                ptr::null(),
+                exec.argv.as_ptr() as *const *mut c_char,
+                environ as *const *mut c_char,
+            );
+            // Returns the error instead of setting errno
+            if ret != 0 {
+                fail("posix_spawn", Error::from_raw_os_error(ret));
+            }
+            pid
+        }
+    };
+    if pid == -1 {
+        error_exit(name);
+    }
+
+    let mut status: c_int = 0;
+    if libc::waitpid(pid, &mut status, 0) == -1 {
+        error_exit("waitpid");
+    }
+    if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 {
+        println!("{} failed (status {:#x})", PROGRAM, status);
+    }
+}
+
+fn fail(what: &str, err: Error) -> ! {
+    println!("Error in {}: {:?}", what, err);
+    process::exit(1);
+}
+
+// Make every page of a heap allocation of this size actually exist, so fork has something to copy
+fn touch_heap(size: usize) -> Vec<u8> {
+    let mut heap = vec![0u8; size];
+    for byte in heap.iter_mut().step_by(4096) {
+        *byte = 1;
+    }
+    heap
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+fn micros(duration: Duration) -> String {
+    format!("{:.1}", duration.as_secs_f64() * 1e6)
+}
+
+pub fn run(count: usize, heap_mb: usize) {
+    let exec = Exec::new();
+    let stack = Stack::new(64 * 1024).unwrap_or_else(|err| {
+        fail("mmap", err);
+    });
+    let _heap = touch_heap(heap_mb * 1024 * 1024);
+
+    println!(
+        "Starting {} {} times each, with {} MiB of heap touched (times in µs)",
+        PROGRAM, count, heap_mb
+    );
+    println!(
+        "{:<16} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9}",
+        "method", "min", "p50", "p90", "p99", "max", "mean"
+    );
+
+    for (method, name) in METHODS {
+        let mut times: Vec<Duration> = (0..count.max(1))
+            .map(|_| {
+                let start = Instant::now();
+                unsafe { spawn_and_reap(method, name, &exec, &stack) };
+                start.elapsed()
+            })
+            .collect();
+        times.sort();
+
+        let mean = times.iter().sum::<Duration>() / times.len() as u32;
+        println!(
+            "{:<16} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9}",
+            name,
+            micros(times[0]),
+            micros(percentile(&times, 0.5)),
+            micros(percentile(&times, 0.9)),
+            micros(percentile(&times, 0.99)),
+            micros(times[times.len() - 1]),
+            micros(mean)
+        );
+    }
+}
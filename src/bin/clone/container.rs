@@ -0,0 +1,99 @@
+// Turning a cloned child into a container: every namespace we can get, its own hostname and /proc,
+// and a different root directory
+
+use crate::error_exit;
+use libc::c_int;
+use std::ffi::CString;
+use std::ptr;
+
+pub const FLAGS: c_int = libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNS
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWNET;
+
+pub const DEFAULT_HOSTNAME: &str = "advent";
+
+pub struct Container {
+    pub rootfs: CString,
+    pub hostname: String,
+}
+
+impl Container {
+    pub fn new(rootfs: &str, hostname: &str) -> Self {
+        Container {
+            rootfs: CString::new(rootfs).expect("rootfs contains a nul byte"),
+            hostname: hostname.to_string(),
+        }
+    }
+
+    // Runs in the child once it's mapped to root in its user namespace. There's nothing to undo
+    // afterwards: the mounts only exist in the child's mount namespace, which goes away with it.
+    pub unsafe fn enter(&self) {
+        if libc::sethostname(
+            self.hostname.as_ptr() as *const libc::c_char,
+            self.hostname.len(),
+        ) == -1
+        {
+            error_exit("sethostname");
+        }
+
+        // Our mount namespace started out as a copy of the parent's, with mounts that propagate
+        // changes back to it. Stop that before touching anything.
+        let root = CString::new("/").unwrap();
+        if libc::mount(
+            ptr::null(),
+            root.as_ptr(),
+            ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            ptr::null(),
+        ) == -1
+        {
+            error_exit("mount --make-rprivate /");
+        }
+
+        // pivot_root wants the new root to be a mount point
+        if libc::mount(
+            self.rootfs.as_ptr(),
+            self.rootfs.as_ptr(),
+            ptr::null(),
+            libc::MS_BIND | libc::MS_REC,
+            ptr::null(),
+        ) == -1
+        {
+            error_exit("mount --rbind rootfs");
+        }
+
+        // Mount /proc while the old one is still around. The kernel only lets a user namespace
+        // mount proc if it can already see a full one, so it can't reveal anything new.
+        let proc_dir = CString::new(format!("{}/proc", self.rootfs.to_str().unwrap())).unwrap();
+        let proc_type = CString::new("proc").unwrap();
+        if libc::mount(
+            proc_type.as_ptr(),
+            proc_dir.as_ptr(),
+            proc_type.as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            ptr::null(),
+        ) == -1
+        {
+            error_exit("mount proc");
+        }
+
+        // Swap the roots. With the same directory for both, the old root ends up stacked on top of
+        // the new one, from where we can detach it without needing a directory to park it in.
+        let dot = CString::new(".").unwrap();
+        if libc::chdir(self.rootfs.as_ptr()) == -1 {
+            error_exit("chdir rootfs");
+        }
+        if libc::syscall(libc::SYS_pivot_root, dot.as_ptr(), dot.as_ptr()) == -1 {
+            error_exit("pivot_root");
+        }
+        if libc::umount2(dot.as_ptr(), libc::MNT_DETACH) == -1 {
+            error_exit("umount old root");
+        }
+        if libc::chdir(root.as_ptr()) == -1 {
+            error_exit("chdir /");
+        }
+    }
+}
@@ -0,0 +1,188 @@
+// A seccomp filter for the child, denying the syscalls given with --deny. Trapped syscalls are
+// reported by a SIGSYS handler, which gets the details in the siginfo like sigaction.rs's handlers do.
+
+use crate::error_exit;
+use advent_2::seccomp::{self, Action, Filter, Program};
+use libc::{c_int, c_long, c_void, sighandler_t, siginfo_t};
+use std::io::{Cursor, Error, Write};
+use std::mem;
+use std::process;
+use std::ptr;
+
+// The SIGSYS part of siginfo_t, which libc doesn't have accessors for
+#[repr(C)]
+struct SigsysInfo {
+    si_signo: c_int,
+    si_errno: c_int,
+    si_code: c_int,
+    _pad: c_int,
+    call_addr: *mut c_void,
+    syscall: c_int,
+    arch: u32,
+}
+
+pub struct Deny {
+    filter: Filter,
+    program: Program,
+}
+
+impl Deny {
+    // Parse a list like `mkdir,getuid:trap,uname:errno=13`. Without an action, the syscall fails
+    // with EPERM.
+    pub fn parse(value: &str) -> Self {
+        let mut filter = Filter::new(Action::Allow);
+
+        for entry in value.split(',') {
+            let (name, action) = entry.split_once(':').unwrap_or((entry, "errno"));
+            let Some(nr) = seccomp::syscall_number(name) else {
+                eprintln!("unknown syscall: {}", name);
+                process::exit(1);
+            };
+
+            let action = match action.split_once('=') {
+                None if action == "errno" => Action::Errno(libc::EPERM as u16),
+                Some(("errno", errno)) => Action::Errno(errno.parse().unwrap_or_else(|_| {
+                    eprintln!("invalid errno: {}", errno);
+                    process::exit(1);
+                })),
+                None if action == "trap" => Action::Trap,
+                None if action == "kill" => Action::Kill,
+                None if action == "log" => Action::Log,
+                _ => {
+                    eprintln!(
+                        "unknown action: {} (try errno, errno=<n>, trap, kill or log)",
+                        action
+                    );
+                    process::exit(1);
+                }
+            };
+            filter = filter.rule(nr, action);
+        }
+
+        let program = filter.assemble();
+        Deny { filter, program }
+    }
+
+    // Runs in the child, as the last thing before its callback or command. A command doesn't inherit
+    // the SIGSYS handler, so for it a trap is as good as a kill.
+    pub unsafe fn install(&self) {
+        if self
+            .filter
+            .rules()
+            .iter()
+            .any(|&(_, action)| action == Action::Trap)
+        {
+            let mut sa = libc::sigaction {
+                sa_sigaction: sa_sigsys as *const () as sighandler_t,
+                sa_mask: mem::zeroed(),
+                sa_flags: libc::SA_SIGINFO,
+                sa_restorer: None,
+            };
+            if libc::sigemptyset(&mut sa.sa_mask) == -1 {
+                error_exit("sigemptyset");
+            }
+            if libc::sigaction(libc::SIGSYS, &sa, ptr::null_mut()) != 0 {
+                error_exit("sigaction for sigsys");
+            }
+        }
+
+        if let Err(err) = seccomp::set_no_new_privs() {
+            println!("Error in prctl(PR_SET_NO_NEW_PRIVS): {:?}", err);
+            process::exit(1);
+        }
+        if let Err(err) = self.program.install() {
+            println!("Error in seccomp: {:?}", err);
+            process::exit(1);
+        }
+    }
+
+    // Make each of the denied syscalls, to see what happens. Most actions stop the call before it
+    // looks at its arguments, but with log it really runs, so it gets arguments that make it fail
+    // or do nothing: -1 where an fd, pid or id goes, and null pointers, zero sizes and signal 0
+    // after that. Some calls don't come back normally whatever the arguments, like fork, which would
+    // leave two of us running the rest of the demo, or vfork, which would run the new child on our
+    // stack. Those are only made when the filter stops them.
+    pub fn try_denied(&self) {
+        for &(nr, action) in self.filter.rules() {
+            let name = seccomp::syscall_name(nr).unwrap_or("?");
+
+            // errno=0 stops the call too, but then a fork would seem to return 0 like in a child
+            let runs = matches!(action, Action::Log | Action::Errno(0));
+            if runs && NOT_RETURNING.contains(&nr) {
+                println!(
+                    "Child: not trying {} ({}), which gets {:?} and so would really run",
+                    name, nr, action
+                );
+                continue;
+            }
+
+            println!("Child: trying {} ({}), which gets {:?}", name, nr, action);
+            let ret = unsafe { libc::syscall(nr, -1, 0, 0, 0, 0, 0) };
+            if ret == -1 {
+                println!("Child: {} failed: {:?}", name, Error::last_os_error());
+            } else {
+                println!("Child: {} returned {}", name, ret);
+            }
+        }
+    }
+}
+
+// Syscalls that start another process or end this one even when given nonsense. By number, since a
+// rule can be given as one too.
+const NOT_RETURNING: &[c_long] = &[
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_fork,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_vfork,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_rt_sigreturn,
+];
+
+// Only async-signal-safe calls in here, so the message is put together on the stack. The trapped
+// syscall could have been made with a lock held, by println for example.
+unsafe extern "C" fn sa_sigsys(_signum: c_int, info: *const siginfo_t, context: *mut c_void) {
+    let info = &*(info as *const SigsysInfo);
+
+    let mut buf = [0u8; 160];
+    let mut cursor = Cursor::new(&mut buf[..]);
+    let _ = writeln!(
+        cursor,
+        "sa_sigsys: syscall {} ({}) denied, arch {:#x}, called from {:?}",
+        info.syscall,
+        seccomp::syscall_name(info.syscall as c_long).unwrap_or("?"),
+        info.arch,
+        info.call_addr
+    );
+    let len = cursor.position() as usize;
+    libc::write(libc::STDOUT_FILENO, buf.as_ptr() as *const c_void, len);
+
+    // The syscall was never made. Whatever is in the return register once we return is what it
+    // returned, so make it look like it failed.
+    let ctx = context as *mut libc::ucontext_t;
+    #[cfg(target_arch = "x86_64")]
+    {
+        (*ctx).uc_mcontext.gregs[libc::REG_RAX as usize] = -libc::EPERM as i64;
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        (*ctx).uc_mcontext.regs[0] = -libc::EPERM as i64 as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NOT_RETURNING;
+    use advent_2::seccomp;
+
+    // So they can be given by name, and are named when they're skipped
+    #[test]
+    fn not_returning_syscalls_have_names() {
+        for &nr in NOT_RETURNING {
+            let name = seccomp::syscall_name(nr).unwrap_or_else(|| panic!("no name for {}", nr));
+            assert_eq!(seccomp::syscall_number(name), Some(nr));
+        }
+    }
+}
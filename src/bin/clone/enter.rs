@@ -0,0 +1,91 @@
+// Joining the namespaces of a running process, like nsenter does, to get a shell in a container.
+
+use crate::{describe_status, error_exit, Command};
+use libc::{c_int, pid_t};
+use std::ffi::CString;
+use std::fs;
+use std::process;
+
+// In the order they get joined. The user namespace has to come first: everything after it needs
+// capabilities in the target's user namespace, which joining it gives us. The mount namespace comes
+// late, since joining it also changes our root directory to the target's.
+pub const NAMESPACES: [(&str, c_int); 6] = [
+    ("user", libc::CLONE_NEWUSER),
+    ("ipc", libc::CLONE_NEWIPC),
+    ("uts", libc::CLONE_NEWUTS),
+    ("net", libc::CLONE_NEWNET),
+    ("pid", libc::CLONE_NEWPID),
+    ("mnt", libc::CLONE_NEWNS),
+];
+
+fn ns_path(pid: &str, ns: &str) -> String {
+    format!("/proc/{}/ns/{}", pid, ns)
+}
+
+// Whether we're already in the same namespace. There's nothing to do then, and setns might not even
+// let us: joining our own user namespace is an error, and the others need capabilities in the user
+// namespace that owns them, which a container's user namespace doesn't have over ours.
+fn same_namespace(pid: pid_t, ns: &str) -> bool {
+    match (
+        fs::read_link(ns_path("self", ns)),
+        fs::read_link(ns_path(&pid.to_string(), ns)),
+    ) {
+        (Ok(ours), Ok(theirs)) => ours == theirs,
+        _ => false,
+    }
+}
+
+pub fn run(pid: pid_t, namespaces: &[&str], command: &[String]) -> ! {
+    // Open everything before joining anything. Once we're in the target's mount namespace, its /proc
+    // might not even show the process.
+    let fds: Vec<(&str, c_int, c_int)> = NAMESPACES
+        .iter()
+        .filter(|(ns, _)| namespaces.contains(ns))
+        .filter(|(ns, _)| {
+            let same = same_namespace(pid, ns);
+            if same {
+                println!("Already in the {} namespace of {}", ns, pid);
+            }
+            !same
+        })
+        .map(|&(ns, flag)| {
+            let path = CString::new(ns_path(&pid.to_string(), ns)).unwrap();
+            let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC) };
+            if fd == -1 {
+                error_exit(&format!("open {}", path.to_str().unwrap()));
+            }
+            (ns, flag, fd)
+        })
+        .collect();
+
+    for &(ns, flag, fd) in &fds {
+        // The flag makes sure the fd really is a namespace of that type
+        if unsafe { libc::setns(fd, flag) } == -1 {
+            error_exit(&format!("setns {}", ns));
+        }
+        unsafe { libc::close(fd) };
+        println!("Joined the {} namespace of {}", ns, pid);
+    }
+
+    let command = Command::new(command);
+
+    // Joining a pid namespace only affects children we start from now on, so we're not in it yet.
+    // Fork once more to get a process that is, and wait for it like a shell would.
+    if fds.iter().any(|&(ns, ..)| ns == "pid") {
+        let child = unsafe { libc::fork() };
+        if child == -1 {
+            error_exit("fork");
+        }
+        if child > 0 {
+            let mut status: c_int = 0;
+            if unsafe { libc::waitpid(child, &mut status, 0) } == -1 {
+                error_exit("waitpid");
+            }
+            let (how, code) = describe_status(status);
+            println!("Command {}", how);
+            process::exit(code);
+        }
+    }
+
+    process::exit(unsafe { command.exec() });
+}
@@ -0,0 +1,209 @@
+// Taking things away from the child before it runs anything. Resource limits we set from out here
+// with prlimit while it waits for the go byte. Privileges it has to give up itself: no_new_privs so
+// nothing it execs can gain any, and all of its capabilities, including the ones it could otherwise
+// get back.
+
+use crate::error_exit;
+use crate::inspect::{self, Caps};
+use advent_2::seccomp;
+use libc::{__rlimit_resource_t, c_ulong, pid_t, rlim_t};
+use std::fs;
+use std::io::Error;
+use std::process;
+use std::ptr;
+
+const RESOURCES: [(__rlimit_resource_t, &str); 6] = [
+    (libc::RLIMIT_NOFILE, "NOFILE"),
+    (libc::RLIMIT_NPROC, "NPROC"),
+    (libc::RLIMIT_AS, "AS"),
+    (libc::RLIMIT_CPU, "CPU"),
+    (libc::RLIMIT_CORE, "CORE"),
+    (libc::RLIMIT_STACK, "STACK"),
+];
+
+pub struct Rlimit {
+    resource: __rlimit_resource_t,
+    pub name: &'static str,
+    soft: rlim_t,
+    hard: rlim_t,
+}
+
+fn parse_limit(value: &str) -> Option<rlim_t> {
+    match value {
+        "unlimited" => Some(libc::RLIM_INFINITY),
+        _ => value.parse().ok(),
+    }
+}
+
+fn format_limit(value: rlim_t) -> String {
+    if value == libc::RLIM_INFINITY {
+        "unlimited".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+impl Rlimit {
+    // Parse something like `NOFILE=64:128`, with `unlimited` for no limit. The units are whatever
+    // setrlimit uses: bytes for AS, CORE and STACK, seconds for CPU.
+    pub fn parse(value: &str) -> Self {
+        let invalid = || -> ! {
+            eprintln!("invalid rlimit: {} (try NAME=<soft>:<hard>)", value);
+            process::exit(1);
+        };
+
+        let Some((name, limits)) = value.split_once('=') else {
+            invalid()
+        };
+        let Some(&(resource, name)) = RESOURCES.iter().find(|(_, known)| *known == name) else {
+            let names: Vec<&str> = RESOURCES.iter().map(|&(_, name)| name).collect();
+            eprintln!("unknown rlimit: {} (try {})", name, names.join(", "));
+            process::exit(1);
+        };
+        let Some((soft, hard)) = limits.split_once(':') else {
+            invalid()
+        };
+        let (Some(soft), Some(hard)) = (parse_limit(soft), parse_limit(hard)) else {
+            invalid()
+        };
+
+        Rlimit {
+            resource,
+            name,
+            soft,
+            hard,
+        }
+    }
+
+    // Anyone can lower the limits of their own processes, but raising a hard limit needs
+    // CAP_SYS_RESOURCE in the initial user namespace. Being root in a new one doesn't help.
+    pub fn apply(&self, pid: pid_t) -> Result<(), Error> {
+        let limit = libc::rlimit {
+            rlim_cur: self.soft,
+            rlim_max: self.hard,
+        };
+        if unsafe { libc::prlimit(pid, self.resource, &limit, ptr::null_mut()) } == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+// Not in libc yet
+const CAP_SETPCAP: u32 = 8;
+
+pub struct Harden {
+    pub no_new_privs: bool,
+    pub drop_caps: bool,
+}
+
+// The highest capability number the kernel knows about
+fn last_capability() -> c_ulong {
+    fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|last| last.trim().parse().ok())
+        .unwrap_or(40)
+}
+
+// The bounding and ambient sets can't be read with capget, only asked about one capability at a time
+fn capability_set(is_set: impl Fn(c_ulong) -> bool) -> u64 {
+    (0..=last_capability())
+        .filter(|&cap| is_set(cap))
+        .fold(0, |set, cap| set | 1 << cap)
+}
+
+impl Harden {
+    // Runs in the child after everything that needs privileges is done
+    pub unsafe fn apply(&self) {
+        if self.no_new_privs {
+            if let Err(err) = seccomp::set_no_new_privs() {
+                println!("Error in prctl(PR_SET_NO_NEW_PRIVS): {:?}", err);
+                process::exit(1);
+            }
+        }
+
+        if self.drop_caps {
+            // The bounding set limits what an exec can give us back, through setuid root binaries or
+            // file capabilities. Dropping from it needs CAP_SETPCAP, so it goes first. Without it,
+            // like in a child that's still in our user namespace and we aren't root, we can only
+            // leave it be.
+            let setpcap =
+                inspect::capabilities().is_ok_and(|caps| caps.effective & (1 << CAP_SETPCAP) != 0);
+            if setpcap {
+                for cap in 0..=last_capability() {
+                    if libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) == -1 {
+                        error_exit("prctl(PR_CAPBSET_DROP)");
+                    }
+                }
+            } else {
+                println!("Child: no CAP_SETPCAP, so the bounding set stays as it is");
+            }
+
+            // Ambient capabilities survive an exec of an ordinary binary
+            if libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_CLEAR_ALL as c_ulong,
+                0,
+                0,
+                0,
+            ) == -1
+            {
+                error_exit("prctl(PR_CAP_AMBIENT_CLEAR_ALL)");
+            }
+
+            let none = Caps {
+                effective: 0,
+                permitted: 0,
+                inheritable: 0,
+            };
+            if let Err(err) = inspect::set_capabilities(&none) {
+                println!("Error in capset: {:?}", err);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+pub fn report() {
+    println!("Child limits:");
+    for (resource, name) in RESOURCES {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if unsafe { libc::getrlimit(resource, &mut limit) } == -1 {
+            error_exit("getrlimit");
+        }
+        println!(
+            "  {:<8} soft {}, hard {}",
+            name,
+            format_limit(limit.rlim_cur),
+            format_limit(limit.rlim_max)
+        );
+    }
+
+    println!("Child privileges:");
+    println!(
+        "  no_new_privs: {}",
+        unsafe { libc::prctl(libc::PR_GET_NO_NEW_PRIVS, 0, 0, 0, 0) } == 1
+    );
+    match inspect::capabilities() {
+        Ok(caps) => println!(
+            "  caps:         effective {:#x}, permitted {:#x}, inheritable {:#x}",
+            caps.effective, caps.permitted, caps.inheritable
+        ),
+        Err(err) => println!("  caps:         (capget: {:?})", err),
+    }
+    let bounding =
+        capability_set(|cap| unsafe { libc::prctl(libc::PR_CAPBSET_READ, cap, 0, 0, 0) == 1 });
+    let ambient = capability_set(|cap| unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_IS_SET as c_ulong,
+            cap,
+            0,
+            0,
+        ) == 1
+    });
+    println!("  bounding:     {:#x}, ambient {:#x}", bounding, ambient);
+}
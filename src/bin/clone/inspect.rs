@@ -0,0 +1,135 @@
+// Everything that decides what a process is allowed to do and who it appears to be, as the process
+// itself sees it. Comparing this before and after the uid and gid maps are written shows where the
+// child's uid 65534 comes from.
+
+use libc::{c_int, pid_t};
+use std::fs;
+use std::io::Error;
+
+const NAMESPACES: [&str; 8] = ["user", "pid", "mnt", "uts", "ipc", "net", "cgroup", "time"];
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+// The capget arguments, which libc doesn't have. Version 3 splits each 64 bit set across two data
+// structs, low half first.
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+pub struct Caps {
+    pub effective: u64,
+    pub permitted: u64,
+    pub inheritable: u64,
+}
+
+pub fn capabilities() -> Result<Caps, Error> {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } == -1 {
+        return Err(Error::last_os_error());
+    }
+
+    let join = |low: u32, high: u32| (high as u64) << 32 | low as u64;
+    Ok(Caps {
+        effective: join(data[0].effective, data[1].effective),
+        permitted: join(data[0].permitted, data[1].permitted),
+        inheritable: join(data[0].inheritable, data[1].inheritable),
+    })
+}
+
+pub fn set_capabilities(caps: &Caps) -> Result<(), Error> {
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [0, 32].map(|shift| CapData {
+        effective: (caps.effective >> shift) as u32,
+        permitted: (caps.permitted >> shift) as u32,
+        inheritable: (caps.inheritable >> shift) as u32,
+    });
+
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+// A map file with the kernel's column padding squeezed out, or a note that nothing was written yet
+fn read_map(path: &str) -> String {
+    match fs::read_to_string(path) {
+        Ok(contents) if contents.trim().is_empty() => "(not written yet)".to_string(),
+        Ok(contents) => contents
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>()
+            .join(", "),
+        Err(err) => format!("({})", err),
+    }
+}
+
+fn read_trimmed(path: &str) -> String {
+    fs::read_to_string(path)
+        .map(|contents| contents.trim().to_string())
+        .unwrap_or_else(|err| format!("({})", err))
+}
+
+pub fn report(who: &str) {
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    println!("{}: uid {}, gid {}", who, uid, gid);
+
+    // The links read like `user:[4026531837]`, where the number is the namespace's inode. Two
+    // processes are in the same namespace exactly when the numbers match.
+    let namespaces: Vec<String> = NAMESPACES
+        .iter()
+        .filter_map(|ns| fs::read_link(format!("/proc/self/ns/{}", ns)).ok())
+        .map(|link| link.to_string_lossy().into_owned())
+        .collect();
+    println!("  namespaces:  {}", namespaces.join(" "));
+
+    println!("  uid_map:     {}", read_map("/proc/self/uid_map"));
+    println!("  gid_map:     {}", read_map("/proc/self/gid_map"));
+
+    // What an id that has no mapping in a namespace shows up as in there
+    println!(
+        "  overflowuid: {}, overflowgid: {}",
+        read_trimmed("/proc/sys/kernel/overflowuid"),
+        read_trimmed("/proc/sys/kernel/overflowgid")
+    );
+
+    match capabilities() {
+        Ok(caps) => println!(
+            "  caps:        effective {:#x}, permitted {:#x}, inheritable {:#x}",
+            caps.effective, caps.permitted, caps.inheritable
+        ),
+        Err(err) => println!("  caps:        (capget: {:?})", err),
+    }
+}
+
+// Our side of someone else's maps. The files show the ids as they are in the namespace of whoever
+// reads them, so from out here the child's root comes out as our own uid.
+pub fn report_maps_of(pid: pid_t) {
+    println!(
+        "  child {} uid_map seen from here: {}",
+        pid,
+        read_map(&format!("/proc/{}/uid_map", pid))
+    );
+    println!(
+        "  child {} gid_map seen from here: {}",
+        pid,
+        read_map(&format!("/proc/{}/gid_map", pid))
+    );
+}
@@ -0,0 +1,1035 @@
+// Reference: https://collaborating.tuhh.de/e-exk4/advent/-/blob/solution_2/02-clone/clone.c
+
+mod bench;
+mod container;
+mod deny;
+mod enter;
+mod harden;
+mod inspect;
+mod net;
+mod sharing;
+mod timens;
+mod uts;
+
+use advent_2::cgroup::{self, Cgroup};
+use advent_2::clone3::{clone3, CloneArgs};
+use advent_2::init;
+use advent_2::pidfd::PidFd;
+use advent_2::stack::{self, Stack};
+use advent_2::thread::{self, JoinHandle};
+use advent_2::userns::{IdMap, Kind};
+use container::Container;
+use deny::Deny;
+use harden::{Harden, Rlimit};
+use libc::{c_char, c_int, c_void, pid_t, siginfo_t};
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::io::Error;
+use std::mem;
+use std::path::Path;
+use std::process;
+use std::ptr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Instant;
+
+// Big enough for println and friends. It's only mapped lazily, so being generous costs nothing.
+const DEFAULT_STACK_SIZE: usize = 1024 * 1024;
+static SHARED: AtomicI32 = AtomicI32::new(0);
+
+// The low byte of the clone flags is the signal sent to the parent when the child exits
+const CSIGNAL: c_int = 0xff;
+
+// Everything the child needs to know, passed through the clone arg
+struct ChildArgs {
+    // The child blocks reading this until the parent has set SHARED, so the output doesn't depend on timing
+    go_fd: c_int,
+
+    // The write end of the go pipe, for a child with its own copy of it to close. Otherwise the read
+    // would never see EOF if the parent went away without saying go.
+    go_write_fd: Option<c_int>,
+
+    // Whether the parent maps us to root in a new user namespace before letting us go
+    user_ns: bool,
+
+    // For inspect mode: the child reports on itself before the maps are written, then says so on this
+    inspect_fd: Option<c_int>,
+
+    // Whether to run the command under our own init instead of exec'ing it, for when the child is the
+    // first process in a new pid namespace
+    init: bool,
+
+    // For a child in a new network namespace: which subnet its link to the parent is on, if it gets one
+    net: Option<u8>,
+
+    container: Option<Container>,
+
+    // For uts mode: the hostname to change to in the child's own uts namespace
+    uts: Option<String>,
+
+    // For time mode: how far ahead to put the clocks of a time namespace for the child's children
+    time_offset: Option<i64>,
+
+    // What to give up before running anything, and whether to show the limits the parent set
+    harden: Option<Harden>,
+    limits: bool,
+
+    // A seccomp filter to install once everything else is set up
+    deny: Option<Deny>,
+
+    command: Option<Command>,
+}
+
+// A command to exec in the child instead of the usual demo. Everything is set up by the parent so the
+// child doesn't need to allocate, which isn't safe for children sharing our memory.
+struct Command {
+    args: Vec<CString>,
+    argv: Vec<*const c_char>,
+}
+
+// The pointers in argv point into args, which moves along with them
+unsafe impl Send for Command {}
+
+impl Command {
+    fn new(args: &[String]) -> Self {
+        let args: Vec<CString> = args
+            .iter()
+            .map(|arg| CString::new(arg.as_str()).expect("argument contains a nul byte"))
+            .collect();
+        let argv = args
+            .iter()
+            .map(|arg| arg.as_ptr())
+            .chain([ptr::null()])
+            .collect();
+
+        Command { args, argv }
+    }
+
+    // Only returns if the exec failed
+    unsafe fn exec(&self) -> c_int {
+        libc::execvp(self.argv[0], self.argv.as_ptr());
+        println!("Error in execvp: {:?}", Error::last_os_error());
+
+        // Like a shell does when it can't run something
+        127
+    }
+}
+
+// How to start the children
+enum Launcher {
+    // The legacy clone call with these flags, exit signal in the low byte
+    Clone(c_int),
+
+    // The thread library
+    Thread,
+
+    // clone3 with CLONE_PIDFD, so everything after that goes through the pidfd
+    Clone3 {
+        exit_signal: c_int,
+        set_tid: Option<pid_t>,
+
+        // Send SIGTERM instead of letting the child continue
+        kill: bool,
+    },
+}
+
+enum Child {
+    // Started with a raw clone. Its stack and arguments have to stay around until it has exited.
+    Process {
+        pid: pid_t,
+        flags: c_int,
+        _stack: Stack,
+        _args: Box<ChildArgs>,
+    },
+
+    // Started with the thread library, which takes care of all that itself
+    Thread(JoinHandle<c_int>),
+
+    // Started with clone3, running on a copy of our stack like after fork
+    Clone3 {
+        pid: pid_t,
+        pidfd: PidFd,
+        exit_signal: c_int,
+    },
+}
+
+fn error_exit(msg: &str) {
+    println!("Error in {}: {:?}", msg, Error::last_os_error());
+    process::exit(1);
+}
+
+extern "C" fn cb(arg: *mut c_void) -> c_int {
+    unsafe { child_main(&*(arg as *const ChildArgs)) }
+}
+
+fn child_main(args: &ChildArgs) -> c_int {
+    unsafe {
+        if let Some(fd) = args.inspect_fd {
+            inspect::report("Child before the maps are written");
+            if libc::write(fd, &1u8 as *const u8 as *const c_void, 1) != 1 {
+                error_exit("write");
+            }
+        }
+
+        if let Some(fd) = args.go_write_fd {
+            libc::close(fd);
+        }
+
+        // Wait for the parent to set shared
+        let mut go = 0u8;
+        if libc::read(args.go_fd, &mut go as *mut u8 as *mut c_void, 1) != 1 {
+            error_exit("read");
+        }
+
+        if args.command.is_none() {
+            println!(
+                "Hello from child! ppid: {}, pid: {}, tid: {}, uid: {}",
+                libc::getppid(),
+                libc::getpid(),
+                libc::gettid(),
+                libc::getuid(),
+            );
+        }
+
+        if args.inspect_fd.is_some() {
+            inspect::report("Child after the maps are written");
+        }
+
+        if args.user_ns {
+            // Until the maps were written we were the overflow ids. Now root in here is us out there.
+            println!("Setgid: {}", libc::setgid(0));
+            println!("Setuid: {}", libc::setuid(0));
+            println!(
+                "Now child sees uid {} gid {}",
+                libc::getuid(),
+                libc::getgid()
+            );
+        }
+
+        if let Some(hostname) = &args.uts {
+            uts::change(hostname);
+        }
+
+        if let Some(offset) = args.time_offset {
+            timens::unshare(offset);
+            timens::report("Child");
+
+            // We're still in the old time namespace, so carry on in a child of our own that isn't
+            let pid = libc::fork();
+            if pid == -1 {
+                error_exit("fork");
+            }
+            if pid > 0 {
+                let mut status: c_int = 0;
+                if libc::waitpid(pid, &mut status, 0) == -1 {
+                    error_exit("waitpid");
+                }
+                let (how, code) = describe_status(status);
+                println!("Grandchild {} {}", pid, how);
+                return code;
+            }
+            timens::report("Grandchild");
+        }
+
+        if let Some(subnet) = args.net {
+            let veth = net::setup(subnet);
+            if args.command.is_none() {
+                net::serve(subnet, veth);
+            }
+        }
+
+        if let Some(container) = &args.container {
+            container.enter();
+        }
+
+        if let Some(harden) = &args.harden {
+            harden.apply();
+        }
+        if args.harden.is_some() || args.limits {
+            harden::report();
+        }
+
+        if let Some(deny) = &args.deny {
+            deny.install();
+            if args.command.is_none() {
+                deny.try_denied();
+            }
+        }
+
+        // Everything is set up, so this is where a real program takes over
+        if let Some(command) = &args.command {
+            if args.init {
+                // We're pid 1 now, so we stick around to reap orphans and pass signals on
+                return match init::run(&command.args) {
+                    Ok(exit) => {
+                        println!("Init: reaped {} orphans", exit.reaped);
+                        exit.code()
+                    }
+                    Err(err) => {
+                        println!("Error in init: {:?}", err);
+                        1
+                    }
+                };
+            }
+            return command.exec();
+        }
+
+        println!("Child sees shared is {}", SHARED.load(Ordering::SeqCst));
+    }
+
+    0
+}
+
+unsafe fn my_fork(flags: c_int, stack: &Stack, arg: &ChildArgs) -> c_int {
+    let arg_void = arg as *const ChildArgs as *mut c_void;
+
+    libc::clone(cb, stack.top(), flags, arg_void)
+}
+
+// Describe a status as returned by waitpid, along with the exit code a shell would report for it
+fn describe_status(status: c_int) -> (String, c_int) {
+    if libc::WIFEXITED(status) {
+        let code = libc::WEXITSTATUS(status);
+        (format!("exited with status {}", code), code)
+    } else if libc::WIFSIGNALED(status) {
+        let signal = libc::WTERMSIG(status);
+        (format!("killed by signal {}", signal), 128 + signal)
+    } else {
+        (format!("changed state (raw status {:#x})", status), 1)
+    }
+}
+
+// Describe a status as returned by waitid, which splits it up differently
+fn describe_siginfo(info: &siginfo_t) -> (String, c_int) {
+    let status = unsafe { info.si_status() };
+    match info.si_code {
+        libc::CLD_EXITED => (format!("exited with status {}", status), status),
+        libc::CLD_KILLED => (format!("killed by signal {}", status), 128 + status),
+        libc::CLD_DUMPED => (
+            format!("killed by signal {} (core dumped)", status),
+            128 + status,
+        ),
+        code => (
+            format!("changed state (code {}, status {})", code, status),
+            1,
+        ),
+    }
+}
+
+// Start a child with clone3. The child never returns from here.
+unsafe fn my_clone3(
+    exit_signal: c_int,
+    set_tid: Option<pid_t>,
+    cgroup: Option<&Cgroup>,
+    arg: &ChildArgs,
+) -> Result<Child, Error> {
+    let mut pidfd: c_int = -1;
+    let tids: Vec<pid_t> = set_tid.into_iter().collect();
+
+    let mut args = CloneArgs {
+        exit_signal: exit_signal as u64,
+        ..Default::default()
+    }
+    .pidfd(&mut pidfd)
+    .set_tid(&tids);
+    if let Some(cgroup) = cgroup {
+        args = args.cgroup(cgroup.as_raw());
+    }
+
+    match clone3(&args) {
+        Ok(0) => process::exit(child_main(arg)),
+        Ok(pid) => Ok(Child::Clone3 {
+            pid,
+            pidfd: PidFd::from_raw(pidfd),
+            exit_signal,
+        }),
+        Err(err) => {
+            if err.raw_os_error() == Some(libc::EPERM) && set_tid.is_some() {
+                println!(
+                    "Choosing the pid needs CAP_SYS_ADMIN in the pid namespace's user namespace"
+                );
+            }
+            Err(err)
+        }
+    }
+}
+
+// The maps for a new user namespace: root inside is us, optionally followed by our subordinate ids.
+// They're checked up front so problems get explained before there's a child to clean up.
+fn id_maps(subids: bool) -> (IdMap, IdMap) {
+    let maps = [Kind::Uid, Kind::Gid].map(|kind| {
+        let mut map = IdMap::own(kind);
+        if subids {
+            map = map.with_subids().unwrap_or_else(|err| {
+                println!("Error reading subordinate ids: {}", err);
+                process::exit(1);
+            });
+        }
+        if let Err(err) = map.validate() {
+            println!("Error: {}", err);
+            process::exit(1);
+        }
+        print!("{:?} map contents:\n{}", kind, map);
+        map
+    });
+
+    let [uid_map, gid_map] = maps;
+    (uid_map, gid_map)
+}
+
+// Pick a pid nobody is using, some way after the last one handed out
+fn free_pid() -> pid_t {
+    let last: pid_t = fs::read_to_string("/proc/sys/kernel/ns_last_pid")
+        .ok()
+        .and_then(|last| last.trim().parse().ok())
+        .unwrap_or(1000);
+
+    (last + 100..)
+        .find(|pid| fs::metadata(format!("/proc/{}", pid)).is_err())
+        .unwrap()
+}
+
+// Check whether a signal is pending without handling it
+fn take_pending(signal: c_int) -> bool {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, signal);
+        let timeout = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        libc::sigtimedwait(&set, ptr::null_mut(), &timeout) == signal
+    }
+}
+
+impl Child {
+    // Kill a child that was never let go and reap it. A thread can't be killed on its own, but it
+    // goes along with the rest of us when we exit, and its stack has to outlive it until then.
+    fn kill(self) {
+        match self {
+            Child::Process { pid, .. } => unsafe {
+                libc::kill(pid, libc::SIGKILL);
+            },
+            Child::Clone3 { ref pidfd, .. } => {
+                // The exit signal may be blocked, so there's nothing to be done if this fails
+                let _ = pidfd.send_signal(libc::SIGKILL);
+            }
+            Child::Thread(handle) => {
+                mem::forget(handle);
+                return;
+            }
+        }
+        self.wait();
+    }
+
+    fn tid(&self) -> pid_t {
+        match self {
+            Child::Process { pid, .. } | Child::Clone3 { pid, .. } => *pid,
+            Child::Thread(handle) => handle.tid(),
+        }
+    }
+
+    // Block until the child has terminated and describe how it went
+    fn wait(self) -> (String, c_int) {
+        match self {
+            Child::Process { pid, flags, .. } => {
+                // A child that doesn't send SIGCHLD when it exits is a "clone" child, which waitpid
+                // ignores unless we ask for it with __WCLONE
+                let mut options = 0;
+                if flags & CSIGNAL != libc::SIGCHLD {
+                    options |= libc::__WCLONE;
+                }
+
+                let mut status: c_int = 0;
+                if unsafe { libc::waitpid(pid, &mut status, options) } == -1 {
+                    error_exit("waitpid");
+                }
+
+                // Only now that the child is gone is it safe to unmap its stack, which happens as we
+                // return and drop the rest of self
+                describe_status(status)
+            }
+
+            // Threads can't be waited for, they just disappear. Joining sleeps on the tid word the
+            // kernel clears once the thread is gone, and hands back what it returned.
+            Child::Thread(handle) => match handle.join() {
+                Ok(status) => (format!("returned {}", status), status),
+                Err(_) => ("panicked".to_string(), 101),
+            },
+
+            // The pidfd becomes readable once the child has exited, and then waitid can reap it
+            // through the pidfd too, without any chance of mixing it up with another process
+            Child::Clone3 {
+                pidfd, exit_signal, ..
+            } => {
+                if let Err(err) = pidfd.poll(-1) {
+                    println!("Error in poll: {:?}", err);
+                    process::exit(1);
+                }
+
+                let mut options = 0;
+                if exit_signal != libc::SIGCHLD {
+                    options |= libc::__WCLONE;
+                }
+                let info = pidfd.wait(options).unwrap_or_else(|err| {
+                    println!("Error in waitid: {:?}", err);
+                    process::exit(1);
+                });
+
+                let (mut how, code) = describe_siginfo(&info);
+                if exit_signal != 0 && exit_signal != libc::SIGCHLD {
+                    if take_pending(exit_signal) {
+                        how += &format!(" and sent us signal {}", exit_signal);
+                    } else {
+                        how += &format!(", but signal {} never arrived", exit_signal);
+                    }
+                }
+                (how, code)
+            }
+        }
+    }
+}
+
+// A child waiting for the go byte: when it was started, its go pipe, whether it has a veth to fetch
+// through, and its cgroup
+type Launched = (Child, Instant, [c_int; 2], bool, Option<Cgroup>);
+
+// A cgroup for one child with the limits from the command line, as (controller, file, value)
+fn limited_cgroup(root: &str, i: usize, limits: &[(&str, &str, String)]) -> Result<Cgroup, Error> {
+    let mut controllers: Vec<&str> = limits.iter().map(|(controller, ..)| *controller).collect();
+    controllers.sort_unstable();
+    controllers.dedup();
+
+    let name = format!("advent-{}-{}", process::id(), i);
+    let cgroup = Cgroup::create(Path::new(root), &name, &controllers)
+        .map_err(|err| Error::new(err.kind(), format!("creating a cgroup: {}", err)))?;
+    for (_, file, value) in limits {
+        cgroup
+            .set(file, value)
+            .map_err(|err| Error::new(err.kind(), format!("setting {}: {}", file, err)))?;
+    }
+
+    Ok(cgroup)
+}
+
+// Give up on launching. The children started so far are all still waiting for the go byte, and
+// would wait forever, so kill and reap them. Exiting doesn't run destructors, so their cgroups (and
+// the one for the child that didn't make it) have to be dropped first, or the directories stay
+// behind.
+fn exit_dropping(children: impl IntoIterator<Item = Launched>, cgroup: Option<Cgroup>) -> ! {
+    for (child, _, go_pipe, _, child_cgroup) in children {
+        child.kill();
+        unsafe {
+            libc::close(go_pipe[0]);
+            libc::close(go_pipe[1]);
+        }
+        drop(child_cgroup);
+    }
+    drop(cgroup);
+    process::exit(1);
+}
+
+// What the child and everything it started used, now that they're gone
+fn report_cgroup(cgroup: &Cgroup) {
+    println!("Cgroup {}:", cgroup.path().display());
+    for file in ["memory.peak", "pids.peak", "cpu.stat"] {
+        match cgroup.get(file) {
+            Ok(value) => println!("  {}: {}", file, value.replace('\n', ", ")),
+            Err(_) => println!("  {}: (not available)", file),
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: clone <fork | chimera | thread | user | inspect | init | net | uts | time | bench | sharing | clone3 | clone3-kill |");
+    eprintln!("              clone3-exit-signal | clone3-tid | container>");
+    eprintln!(
+        "             [--stack-size=<bytes>] [--count=<n>] [--tid=<pid>] [--heap-mb=<n>] [--rootfs=<dir>] [--hostname=<name>]"
+    );
+    eprintln!("             [--offset=<seconds>] [--rlimit=<NOFILE | NPROC | AS | CPU | CORE | STACK>=<soft>:<hard>]...");
+    eprintln!("             [--no-new-privs] [--drop-caps]");
+    eprintln!("             [--subids] [--init] [--memory-max=<bytes>] [--pids-max=<n>] [--cpu-max=<quota>[/<period>]]");
+    eprintln!("             [--cgroup-root=<dir>] [--deny=<syscall>[:<errno[=<n>] | trap | kill | log>],...]");
+    eprintln!("             [-- <command> [args...]]");
+    eprintln!("       clone enter <pid> [--user] [--ipc] [--uts] [--net] [--pid] [--mnt] [-- <command> [args...]]");
+    process::exit(1);
+}
+
+fn parse_number<T: FromStr>(arg: &str, value: &str) -> T {
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("invalid value for {}: {}", arg, value);
+        process::exit(1);
+    })
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    // Everything after -- is a command to run in the child
+    let (args, mut command) = match args.iter().position(|arg| arg == "--") {
+        Some(i) => (&args[..i], Some(args[i + 1..].to_vec())),
+        None => (&args[..], None),
+    };
+
+    if args.len() < 2 || command.as_ref().is_some_and(|command| command.is_empty()) {
+        usage();
+    }
+
+    // Joining namespaces works differently from everything else, starting with the arguments
+    if args[1] == "enter" {
+        let Some(pid) = args.get(2) else { usage() };
+        let pid = parse_number::<usize>("pid", pid) as pid_t;
+
+        let mut namespaces: Vec<&str> = Vec::new();
+        for arg in &args[3..] {
+            match arg.strip_prefix("--") {
+                Some(ns) if enter::NAMESPACES.iter().any(|&(known, _)| known == ns) => {
+                    namespaces.push(ns)
+                }
+                _ => usage(),
+            }
+        }
+        if namespaces.is_empty() {
+            namespaces = enter::NAMESPACES.iter().map(|&(ns, _)| ns).collect();
+        }
+
+        let command = command.unwrap_or_else(|| vec!["/bin/sh".to_string()]);
+        enter::run(pid, &namespaces, &command);
+    }
+
+    let mut stack_size = DEFAULT_STACK_SIZE;
+    let mut count = None;
+    let mut heap_mb = 0;
+    let mut tid = None;
+    let mut rootfs = None;
+    let mut hostname = container::DEFAULT_HOSTNAME;
+    let mut subids = false;
+    let mut init = false;
+    let mut network = false;
+    let mut limits = Vec::new();
+    let mut cgroup_root = cgroup::DEFAULT_ROOT;
+    let mut deny = None;
+    let mut offset = timens::DEFAULT_OFFSET;
+    let mut rlimits = Vec::new();
+    let mut no_new_privs = false;
+    let mut drop_caps = false;
+    for arg in args.iter().skip(2) {
+        match arg.split_once('=') {
+            None if arg == "--subids" => subids = true,
+            None if arg == "--init" => init = true,
+            None if arg == "--no-new-privs" => no_new_privs = true,
+            None if arg == "--drop-caps" => drop_caps = true,
+            Some(("--rootfs", value)) => rootfs = Some(value),
+            Some(("--hostname", value)) => hostname = value,
+            Some(("--stack-size", value)) => {
+                stack_size = parse_number(arg, value);
+                if stack_size < stack::MIN_SIZE {
+                    eprintln!(
+                        "--stack-size must be at least {} bytes, got {}",
+                        stack::MIN_SIZE,
+                        stack_size
+                    );
+                    process::exit(1);
+                }
+            }
+            Some(("--count", value)) => count = Some(parse_number(arg, value)),
+            Some(("--heap-mb", value)) => heap_mb = parse_number(arg, value),
+            Some(("--tid", value)) => tid = Some(parse_number::<usize>(arg, value) as pid_t),
+            Some(("--memory-max", value)) => {
+                limits.push(("memory", "memory.max", value.to_string()))
+            }
+            Some(("--pids-max", value)) => limits.push(("pids", "pids.max", value.to_string())),
+            // cpu.max wants "<quota> <period>", which is awkward to pass as one argument
+            Some(("--cpu-max", value)) => limits.push(("cpu", "cpu.max", value.replace('/', " "))),
+            Some(("--cgroup-root", value)) => cgroup_root = value,
+            Some(("--deny", value)) => deny = Some(value),
+            Some(("--rlimit", value)) => rlimits.push(Rlimit::parse(value)),
+            Some(("--offset", value)) => offset = parse_number(arg, value),
+            _ => usage(),
+        }
+    }
+
+    unsafe {
+        let mut launcher = Launcher::Thread;
+        let mut maps = None;
+        let mut container = None;
+        let mut inspect = false;
+        let mut uts = false;
+        let mut time = false;
+
+        match args.get(1).unwrap().as_str() {
+            "bench" => {
+                bench::run(count.unwrap_or(bench::DEFAULT_COUNT), heap_mb);
+                process::exit(0);
+            }
+            "sharing" => {
+                sharing::run();
+                process::exit(0);
+            }
+            "fork" => {
+                launcher = Launcher::Clone(libc::SIGCHLD);
+            }
+            "chimera" => {
+                launcher = Launcher::Clone(libc::SIGCHLD | libc::CLONE_VM);
+            }
+            "thread" => {
+                if command.is_some() {
+                    println!("Note: exec in a thread replaces the whole process, parent included");
+                }
+            }
+            "user" => {
+                launcher = Launcher::Clone(libc::SIGCHLD | libc::CLONE_NEWUSER);
+
+                // Make sure we're running from somewhere where it'll do something
+                let uid = libc::getuid();
+                if uid == 0 {
+                    println!(
+                        "You are already root! Try `su advent -c \"./target/debug/clone user\"`"
+                    )
+                }
+
+                maps = Some(id_maps(subids));
+            }
+            "inspect" => {
+                launcher = Launcher::Clone(libc::SIGCHLD | libc::CLONE_NEWUSER);
+                inspect = true;
+                maps = Some(id_maps(subids));
+                inspect::report("Parent before the maps are written");
+            }
+            "net" => {
+                launcher =
+                    Launcher::Clone(libc::SIGCHLD | libc::CLONE_NEWUSER | libc::CLONE_NEWNET);
+                network = true;
+                maps = Some(id_maps(subids));
+            }
+            "uts" => {
+                launcher =
+                    Launcher::Clone(libc::SIGCHLD | libc::CLONE_NEWUSER | libc::CLONE_NEWUTS);
+                uts = true;
+                maps = Some(id_maps(subids));
+                uts::report("Parent");
+            }
+            "time" => {
+                // Just the user namespace, so the child has the capabilities to unshare the rest
+                launcher = Launcher::Clone(libc::SIGCHLD | libc::CLONE_NEWUSER);
+                time = true;
+                maps = Some(id_maps(subids));
+                timens::report("Parent");
+            }
+            "init" => {
+                launcher =
+                    Launcher::Clone(libc::SIGCHLD | libc::CLONE_NEWUSER | libc::CLONE_NEWPID);
+                init = true;
+                maps = Some(id_maps(subids));
+                if command.is_none() {
+                    command = Some(vec!["/bin/sh".to_string()]);
+                }
+            }
+            "container" => {
+                launcher = Launcher::Clone(libc::SIGCHLD | container::FLAGS);
+
+                let Some(rootfs) = rootfs else {
+                    eprintln!(
+                        "container needs --rootfs=<dir>, a directory with a /proc to mount on"
+                    );
+                    process::exit(1);
+                };
+                container = Some(rootfs);
+
+                maps = Some(id_maps(subids));
+                if command.is_none() {
+                    command = Some(vec!["/bin/sh".to_string()]);
+                }
+            }
+            "clone3" => {
+                launcher = Launcher::Clone3 {
+                    exit_signal: libc::SIGCHLD,
+                    set_tid: None,
+                    kill: false,
+                };
+            }
+            "clone3-kill" => {
+                launcher = Launcher::Clone3 {
+                    exit_signal: libc::SIGCHLD,
+                    set_tid: None,
+                    kill: true,
+                };
+            }
+            "clone3-exit-signal" => {
+                // Block the signal so it stays pending instead of killing us, and we can check for it
+                // after the child is gone
+                let mut set: libc::sigset_t = mem::zeroed();
+                libc::sigemptyset(&mut set);
+                libc::sigaddset(&mut set, libc::SIGUSR1);
+                libc::sigprocmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+
+                launcher = Launcher::Clone3 {
+                    exit_signal: libc::SIGUSR1,
+                    set_tid: None,
+                    kill: false,
+                };
+            }
+            "clone3-tid" => {
+                let tid = tid.unwrap_or_else(free_pid);
+                println!("Asking for pid {}", tid);
+                launcher = Launcher::Clone3 {
+                    exit_signal: libc::SIGCHLD,
+                    set_tid: Some(tid),
+                    kill: false,
+                };
+            }
+            _ => {
+                usage();
+            }
+        };
+
+        println!(
+            "Hello from parent! ppid: {}, pid: {}, tid: {}, uid: {}",
+            libc::getppid(),
+            libc::getpid(),
+            libc::gettid(),
+            libc::getuid()
+        );
+
+        // Threads can't be moved into a cgroup of their own, only whole processes. Resource limits
+        // belong to the whole process too, so a thread's would be ours.
+        if (!limits.is_empty() || !rlimits.is_empty()) && matches!(launcher, Launcher::Thread) {
+            eprintln!("cgroup and resource limits need a child process, not a thread");
+            process::exit(1);
+        }
+
+        // Each child's link gets one of the 10.200.<n>.0 subnets, and there are only so many of those
+        if network && count.unwrap_or(1) > net::MAX_CHILDREN {
+            eprintln!(
+                "net gives every child a subnet of its own, so --count can be at most {}",
+                net::MAX_CHILDREN
+            );
+            process::exit(1);
+        }
+
+        // Every child gets its own stack, arguments and go pipe
+        let count = count.unwrap_or(1);
+        let mut children: Vec<Launched> = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut go_pipe: [c_int; 2] = [-1, -1];
+            // Close on exec, so a command run in the child doesn't inherit it
+            if libc::pipe2(go_pipe.as_mut_ptr(), libc::O_CLOEXEC) == -1 {
+                error_exit("pipe");
+            }
+
+            let mut inspect_pipe: [c_int; 2] = [-1, -1];
+            if inspect && libc::pipe2(inspect_pipe.as_mut_ptr(), libc::O_CLOEXEC) == -1 {
+                error_exit("pipe");
+            }
+
+            // Threads and children sharing our file descriptor table would close it for us too
+            let own_files = match launcher {
+                Launcher::Clone(flags) => flags & libc::CLONE_FILES == 0,
+                Launcher::Thread => false,
+                Launcher::Clone3 { .. } => true,
+            };
+
+            let child_args = Box::new(ChildArgs {
+                go_fd: go_pipe[0],
+                go_write_fd: own_files.then_some(go_pipe[1]),
+                user_ns: maps.is_some(),
+                inspect_fd: inspect.then_some(inspect_pipe[1]),
+                init,
+                net: network.then_some(i as u8),
+                container: container.map(|rootfs| Container::new(rootfs, hostname)),
+                uts: uts.then(|| hostname.to_string()),
+                time_offset: time.then_some(offset),
+                harden: (no_new_privs || drop_caps).then_some(Harden {
+                    no_new_privs,
+                    drop_caps,
+                }),
+                limits: !rlimits.is_empty(),
+                deny: deny.map(Deny::parse),
+                command: command.as_deref().map(Command::new),
+            });
+
+            let cgroup = if limits.is_empty() {
+                None
+            } else {
+                match limited_cgroup(cgroup_root, i, &limits) {
+                    Ok(cgroup) => Some(cgroup),
+                    Err(err) => {
+                        println!("Error {}", err);
+                        exit_dropping(children, None);
+                    }
+                }
+            };
+
+            let start = Instant::now();
+            let child = match launcher {
+                Launcher::Clone(flags) => {
+                    let stack = match Stack::new(stack_size) {
+                        Ok(stack) => stack,
+                        Err(err) => {
+                            println!("Error in mmap: {:?}", err);
+                            exit_dropping(children, cgroup);
+                        }
+                    };
+
+                    let pid = my_fork(flags, &stack, &child_args);
+                    if pid == -1 {
+                        println!("Error in clone: {:?}", Error::last_os_error());
+                        exit_dropping(children, cgroup);
+                    }
+                    let child = Child::Process {
+                        pid,
+                        flags,
+                        _stack: stack,
+                        _args: child_args,
+                    };
+
+                    // The child is still waiting for the go byte, so it can't get anything done
+                    // before the limits apply
+                    if let Some(err) = cgroup.as_ref().and_then(|cgroup| cgroup.add(pid).err()) {
+                        println!("Error moving the child into its cgroup: {}", err);
+                        child.kill();
+                        exit_dropping(children, cgroup);
+                    }
+
+                    child
+                }
+                Launcher::Thread => {
+                    let spawned = thread::Builder::new()
+                        .stack_size(stack_size)
+                        .spawn(move || child_main(&child_args));
+                    match spawned {
+                        Ok(handle) => Child::Thread(handle),
+                        Err(err) => {
+                            println!("Error in clone: {:?}", err);
+                            exit_dropping(children, None);
+                        }
+                    }
+                }
+                Launcher::Clone3 {
+                    exit_signal,
+                    set_tid,
+                    ..
+                } => match my_clone3(
+                    exit_signal,
+                    set_tid.map(|tid| tid + i as pid_t),
+                    cgroup.as_ref(),
+                    &child_args,
+                ) {
+                    Ok(child) => child,
+                    Err(err) => {
+                        println!("Error in clone3: {:?}", err);
+                        exit_dropping(children, cgroup);
+                    }
+                },
+            };
+            println!("child tid is {}", child.tid());
+
+            // Like the cgroup, these are in place before the child gets going
+            for rlimit in &rlimits {
+                if let Err(err) = rlimit.apply(child.tid()) {
+                    println!("Error setting RLIMIT_{}: {}", rlimit.name, err);
+                    child.kill();
+                    exit_dropping(children, cgroup);
+                }
+            }
+
+            // The child is waiting for the go byte, so it can't look at its ids before they're mapped.
+            // Doing it from out here is the only way to map more than our own id, which needs
+            // capabilities in this namespace rather than the child's.
+            if let Some((uid_map, gid_map)) = &maps {
+                if inspect {
+                    let mut done = 0u8;
+                    if libc::read(inspect_pipe[0], &mut done as *mut u8 as *mut c_void, 1) != 1 {
+                        error_exit("read");
+                    }
+                    libc::close(inspect_pipe[0]);
+                    libc::close(inspect_pipe[1]);
+                }
+
+                if let Err(err) = gid_map
+                    .write(child.tid())
+                    .and_then(|()| uid_map.write(child.tid()))
+                {
+                    println!("Error: {}", err);
+                    child.kill();
+                    exit_dropping(children, cgroup);
+                }
+
+                if inspect {
+                    inspect::report("Parent after the maps are written");
+                    inspect::report_maps_of(child.tid());
+                }
+            }
+
+            // The child's end of the veth pair has to exist before it's let go, so it can set it up
+            let veth = network && net::connect_child(child.tid(), i as u8);
+
+            children.push((child, start, go_pipe, veth, cgroup));
+        }
+
+        if command.is_none() {
+            println!("Parent: setting shared to 1");
+            SHARED.store(1, Ordering::SeqCst);
+            println!("Parent sees shared is {}", SHARED.load(Ordering::SeqCst));
+        }
+
+        // Pass on how the (last unsuccessful) child did as our own exit status
+        let mut exit_code = 0;
+
+        // Let the children continue one at a time. The chimera children share our memory, and without
+        // CLONE_SETTLS they run on our thread pointer too, so they share our thread-local storage as
+        // well. If they ran concurrently they would trip over each other in println.
+        let mut children = children.into_iter().enumerate();
+        while let Some((i, (child, start, go_pipe, veth, cgroup))) = children.next() {
+            match &child {
+                Child::Clone3 { pidfd, .. }
+                    if matches!(launcher, Launcher::Clone3 { kill: true, .. }) =>
+                {
+                    println!(
+                        "Parent: sending SIGTERM to child {} through its pidfd",
+                        child.tid()
+                    );
+                    if let Err(err) = pidfd.send_signal(libc::SIGTERM) {
+                        println!("Error in pidfd_send_signal: {:?}", err);
+                        child.kill();
+                        libc::close(go_pipe[0]);
+                        libc::close(go_pipe[1]);
+                        exit_dropping(children.map(|(_, launched)| launched), cgroup);
+                    }
+                }
+                _ => {
+                    if libc::write(go_pipe[1], &1u8 as *const u8 as *const c_void, 1) != 1 {
+                        error_exit("write");
+                    }
+                }
+            }
+
+            if veth && command.is_none() {
+                net::fetch(i as u8);
+            }
+
+            let tid = child.tid();
+            let (how, code) = child.wait();
+            println!("Child {} {} after {:?}", tid, how, start.elapsed());
+            if let Some(cgroup) = cgroup {
+                report_cgroup(&cgroup);
+            }
+            if uts {
+                uts::report("Parent still has");
+            }
+            if time {
+                timens::report("Parent");
+            }
+            if code != 0 {
+                exit_code = code;
+            }
+
+            libc::close(go_pipe[0]);
+            libc::close(go_pipe[1]);
+        }
+
+        process::exit(exit_code);
+    }
+}
@@ -0,0 +1,162 @@
+// Networking for a child in its own network namespace. It starts out with nothing but a loopback
+// link that's down, so not even 127.0.0.1 works until it's brought up. With the privileges for it, the
+// parent also connects itself to the child with a veth pair, so the child can serve something to it.
+
+use advent_2::netlink::{self, Netlink};
+use libc::pid_t;
+use std::io::{Error, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PORT: u16 = 7878;
+
+// The child's end of the veth pair, named the same in every child since it lives in the child's
+// namespace. The parent's end is named after the child.
+const CHILD_LINK: &str = "eth0";
+const PREFIX_LEN: u8 = 24;
+
+// As many as there are subnets for, see below
+pub const MAX_CHILDREN: usize = u8::MAX as usize + 1;
+
+// Each child gets a subnet of its own, with the parent at .1 and the child at .2
+fn parent_address(subnet: u8) -> Ipv4Addr {
+    Ipv4Addr::new(10, 200, subnet, 1)
+}
+
+fn child_address(subnet: u8) -> Ipv4Addr {
+    Ipv4Addr::new(10, 200, subnet, 2)
+}
+
+// Create the veth pair and set up our end. Returns whether there is one, which needs CAP_NET_ADMIN
+// in our own network namespace.
+pub fn connect_child(child: pid_t, subnet: u8) -> bool {
+    let name = format!("veth{}", child);
+    let result = Netlink::open().and_then(|mut netlink| {
+        netlink.add_veth(&name, CHILD_LINK, child)?;
+        let index = netlink::link_index(&name)?;
+        netlink.add_address(index, parent_address(subnet), PREFIX_LEN)?;
+        netlink.set_up(index)
+    });
+
+    match result {
+        Ok(()) => {
+            println!(
+                "Parent: {} is {}/{}, connected to the child's {}",
+                name,
+                parent_address(subnet),
+                PREFIX_LEN,
+                CHILD_LINK
+            );
+            true
+        }
+        Err(err) if err.raw_os_error() == Some(libc::EPERM) => {
+            println!("Parent: creating a veth pair needs CAP_NET_ADMIN, the child only gets lo");
+            false
+        }
+        Err(err) => {
+            println!("Parent: could not create a veth pair: {}", err);
+            false
+        }
+    }
+}
+
+// Runs in the child, which is root in its user namespace and so has CAP_NET_ADMIN in its network
+// namespace. Returns whether there's a link to the parent.
+pub fn setup(subnet: u8) -> bool {
+    let before = TcpStream::connect((Ipv4Addr::LOCALHOST, PORT));
+    if let Err(err) = before {
+        println!("Child: connecting to localhost with lo down: {}", err);
+    }
+
+    let mut netlink = Netlink::open().unwrap_or_else(|err| fail("netlink socket", err));
+    let lo = netlink::link_index("lo").unwrap_or_else(|err| fail("finding lo", err));
+    netlink
+        .set_up(lo)
+        .unwrap_or_else(|err| fail("bringing up lo", err));
+    println!("Child: lo is up");
+
+    // The parent created our end of the veth pair before letting us go, if it could
+    let Ok(index) = netlink::link_index(CHILD_LINK) else {
+        return false;
+    };
+    netlink
+        .add_address(index, child_address(subnet), PREFIX_LEN)
+        .unwrap_or_else(|err| fail("adding an address", err));
+    netlink
+        .set_up(index)
+        .unwrap_or_else(|err| fail("bringing up the veth", err));
+    println!(
+        "Child: {} is {}/{}",
+        CHILD_LINK,
+        child_address(subnet),
+        PREFIX_LEN
+    );
+    true
+}
+
+// Answer one connection, from the parent if there's a link to it or from ourselves otherwise
+pub fn serve(subnet: u8, veth: bool) {
+    let address = if veth {
+        child_address(subnet)
+    } else {
+        Ipv4Addr::LOCALHOST
+    };
+    let listener =
+        TcpListener::bind((address, PORT)).unwrap_or_else(|err| fail("binding a socket", err));
+    println!("Child: listening on {}:{}", address, PORT);
+
+    // Without a veth there's nobody else who could connect. The connection completes as soon as
+    // the kernel has queued it for accepting, so we can do both ends.
+    let client = (!veth).then(|| {
+        TcpStream::connect((address, PORT))
+            .unwrap_or_else(|err| fail("connecting to ourselves", err))
+    });
+
+    let (mut stream, peer) = listener
+        .accept()
+        .unwrap_or_else(|err| fail("accepting a connection", err));
+    writeln!(
+        stream,
+        "Hello from the child's network namespace, {}!",
+        peer.ip()
+    )
+    .unwrap_or_else(|err| fail("writing to the connection", err));
+    drop(stream);
+
+    if let Some(client) = client {
+        read_greeting("Child", client);
+    }
+}
+
+// Runs in the parent once the child has been let go, giving it some time to start listening
+pub fn fetch(subnet: u8) {
+    let address = (child_address(subnet), PORT);
+    let start = Instant::now();
+    let stream = loop {
+        match TcpStream::connect(address) {
+            Ok(stream) => break stream,
+            Err(_) if start.elapsed() < Duration::from_secs(2) => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            Err(err) => {
+                println!("Parent: could not connect to the child: {}", err);
+                return;
+            }
+        }
+    };
+    read_greeting("Parent", stream);
+}
+
+fn read_greeting(who: &str, mut stream: TcpStream) {
+    let mut greeting = String::new();
+    match stream.read_to_string(&mut greeting) {
+        Ok(_) => print!("{} got: {}", who, greeting),
+        Err(err) => println!("{}: error reading from the connection: {}", who, err),
+    }
+}
+
+fn fail(what: &str, err: Error) -> ! {
+    println!("Error {}: {}", what, err);
+    std::process::exit(1);
+}
@@ -0,0 +1,205 @@
+// What the child shares with us besides memory. For every combination of CLONE_FILES, CLONE_FS and
+// CLONE_SIGHAND the child changes its file descriptors, working directory, umask and a signal
+// handler, and we look at whether ours changed along with them.
+
+use crate::{error_exit, DEFAULT_STACK_SIZE};
+use advent_2::stack::Stack;
+use libc::{c_int, c_void, mode_t};
+use std::env;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+const SIGNAL: c_int = libc::SIGUSR2;
+
+const FLAGS: [(c_int, &str); 3] = [
+    (libc::CLONE_FILES, "CLONE_FILES"),
+    (libc::CLONE_FS, "CLONE_FS"),
+    (libc::CLONE_SIGHAND, "CLONE_SIGHAND"),
+];
+
+// Everything is prepared by us, and the child only makes syscalls. It shares our memory (CLONE_SIGHAND
+// insists on that), and without CLONE_SETTLS it runs on our thread pointer, so anything it did with
+// thread-local storage would be done to ours. It must not touch any.
+struct Changes {
+    dev_null: CString,
+
+    // Both different from ours, so whether ours changed says whether they're shared
+    dir: CString,
+    umask: mode_t,
+
+    // The child closes this one of ours
+    close_fd: c_int,
+
+    // and opens a new one, which it tells us about here
+    opened_fd: AtomicI32,
+}
+
+extern "C" fn on_signal(_signum: c_int) {}
+
+extern "C" fn change_things(arg: *mut c_void) -> c_int {
+    unsafe {
+        let changes = &*(arg as *const Changes);
+
+        // Open first, so the new fd can't get the number of the one we close
+        let fd = libc::open(changes.dev_null.as_ptr(), libc::O_RDONLY);
+        changes.opened_fd.store(fd, Ordering::SeqCst);
+        libc::close(changes.close_fd);
+
+        libc::chdir(changes.dir.as_ptr());
+        libc::umask(changes.umask);
+
+        let mut sa: libc::sigaction = mem::zeroed();
+        sa.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+        libc::sigaction(SIGNAL, &sa, ptr::null_mut());
+    }
+    0
+}
+
+fn fd_is_open(fd: c_int) -> bool {
+    fd >= 0 && unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1
+}
+
+fn current_umask() -> mode_t {
+    unsafe {
+        let mask = libc::umask(0);
+        libc::umask(mask);
+        mask
+    }
+}
+
+fn current_handler() -> libc::sighandler_t {
+    unsafe {
+        let mut sa: libc::sigaction = mem::zeroed();
+        libc::sigaction(SIGNAL, ptr::null(), &mut sa);
+        sa.sa_sigaction
+    }
+}
+
+fn describe(shared: bool, expected: bool) -> String {
+    let observed = if shared { "shared" } else { "not shared" };
+    if shared == expected {
+        observed.to_string()
+    } else {
+        format!("{} (unexpected!)", observed)
+    }
+}
+
+// A new, empty directory for the child to change to, which can't be where we are already
+fn make_child_dir() -> PathBuf {
+    let mut template = *b"/tmp/advent-sharing-XXXXXX\0";
+    if unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+        error_exit("mkdtemp");
+    }
+    let dir = CStr::from_bytes_with_nul(&template).unwrap();
+    PathBuf::from(dir.to_str().unwrap())
+}
+
+// Start a child with these flags, wait for it to make its changes, and report which of them we see
+unsafe fn check(flags: c_int, stack: &Stack, child_dir: &Path) -> [(bool, bool); 4] {
+    let dir = env::current_dir().expect("no current directory");
+    let mask = current_umask();
+    let handler = current_handler();
+
+    let dev_null = CString::new("/dev/null").unwrap();
+    let changes = Changes {
+        close_fd: libc::open(dev_null.as_ptr(), libc::O_RDONLY),
+        dev_null,
+        dir: CString::new(child_dir.as_os_str().as_encoded_bytes()).unwrap(),
+        umask: !mask & 0o777,
+        opened_fd: AtomicI32::new(-1),
+    };
+    if changes.close_fd == -1 {
+        error_exit("open");
+    }
+
+    let pid = libc::clone(
+        change_things,
+        stack.top(),
+        flags | libc::CLONE_VM | libc::SIGCHLD,
+        &changes as *const Changes as *mut c_void,
+    );
+    if pid == -1 {
+        error_exit("clone");
+    }
+    let mut status = 0;
+    if libc::waitpid(pid, &mut status, 0) == -1 {
+        error_exit("waitpid");
+    }
+
+    let opened_fd = changes.opened_fd.load(Ordering::SeqCst);
+    let observed = [
+        (
+            fd_is_open(opened_fd) && !fd_is_open(changes.close_fd),
+            flags & libc::CLONE_FILES != 0,
+        ),
+        (
+            env::current_dir().is_ok_and(|cwd| cwd != dir),
+            flags & libc::CLONE_FS != 0,
+        ),
+        (current_umask() != mask, flags & libc::CLONE_FS != 0),
+        (
+            current_handler() != handler,
+            flags & libc::CLONE_SIGHAND != 0,
+        ),
+    ];
+
+    // Put everything back for the next child
+    for fd in [changes.close_fd, opened_fd] {
+        if fd_is_open(fd) {
+            libc::close(fd);
+        }
+    }
+    env::set_current_dir(dir).expect("could not change back to our directory");
+    libc::umask(mask);
+    libc::signal(SIGNAL, handler);
+
+    observed
+}
+
+pub fn run() {
+    let stack = Stack::new(DEFAULT_STACK_SIZE).unwrap_or_else(|err| {
+        println!("Error in mmap: {:?}", err);
+        std::process::exit(1);
+    });
+
+    let child_dir = make_child_dir();
+
+    println!(
+        "The child closes an fd and opens one, changes to {}, sets its umask to {:03o} and \
+         installs a handler for signal {}",
+        child_dir.display(),
+        !current_umask() & 0o777,
+        SIGNAL
+    );
+    println!("Every child also gets CLONE_VM, which CLONE_SIGHAND needs");
+    println!(
+        "{:<40} {:<24} {:<24} {:<24} signal handlers",
+        "flags", "fds", "cwd", "umask"
+    );
+
+    for combination in 0..1 << FLAGS.len() {
+        let mut flags = 0;
+        let mut names = Vec::new();
+        for (i, &(flag, name)) in FLAGS.iter().enumerate() {
+            if combination & (1 << i) != 0 {
+                flags |= flag;
+                names.push(name);
+            }
+        }
+        if names.is_empty() {
+            names.push("none");
+        }
+
+        let observed = unsafe { check(flags, &stack, &child_dir) };
+        let columns: Vec<String> = observed
+            .iter()
+            .map(|&(shared, expected)| format!("{:<24}", describe(shared, expected)))
+            .collect();
+        println!("{:<40} {}", names.join(" | "), columns.join(" ").trim_end());
+    }
+    let _ = fs::remove_dir(&child_dir);
+}
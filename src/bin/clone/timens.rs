@@ -0,0 +1,57 @@
+// Time namespaces give CLOCK_MONOTONIC and CLOCK_BOOTTIME an offset, so a container can be moved to
+// another machine without its clocks jumping. CLOCK_REALTIME isn't affected, it's the same everywhere.
+//
+// The offsets can only be set before any process is in the namespace, and clone can't create one
+// because the flag overlaps the exit signal. So it goes through unshare, which doesn't move the caller
+// but only its future children, and the offsets get written in between.
+
+use crate::error_exit;
+use libc::{c_int, clockid_t};
+use std::fs;
+use std::process;
+
+// Not in libc yet
+pub const CLONE_NEWTIME: c_int = 0x80;
+
+pub const DEFAULT_OFFSET: i64 = 10 * 24 * 60 * 60;
+
+const CLOCKS: [(clockid_t, &str); 3] = [
+    (libc::CLOCK_MONOTONIC, "CLOCK_MONOTONIC"),
+    (libc::CLOCK_BOOTTIME, "CLOCK_BOOTTIME"),
+    (libc::CLOCK_REALTIME, "CLOCK_REALTIME"),
+];
+
+fn seconds(clock: clockid_t) -> f64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { libc::clock_gettime(clock, &mut ts) } == -1 {
+        error_exit("clock_gettime");
+    }
+    ts.tv_sec as f64 + ts.tv_nsec as f64 / 1e9
+}
+
+pub fn report(who: &str) {
+    let clocks: Vec<String> = CLOCKS
+        .iter()
+        .map(|&(clock, name)| format!("{} {:.3}", name, seconds(clock)))
+        .collect();
+    println!("{}: {}", who, clocks.join(", "));
+}
+
+// Create a time namespace for our children with both clocks shifted by this many seconds. Needs
+// CAP_SYS_ADMIN, which a child in a new user namespace has.
+pub unsafe fn unshare(offset: i64) {
+    if libc::unshare(CLONE_NEWTIME) == -1 {
+        error_exit("unshare(CLONE_NEWTIME)");
+    }
+
+    // The file belongs to the namespace our children will be in, not the one we're in
+    let offsets = format!("monotonic {} 0\nboottime {} 0\n", offset, offset);
+    if let Err(err) = fs::write("/proc/self/timens_offsets", &offsets) {
+        println!("Error writing /proc/self/timens_offsets: {:?}", err);
+        process::exit(1);
+    }
+    println!("Child: unshared a time namespace shifted by {}s", offset);
+}
@@ -0,0 +1,41 @@
+// A uts namespace only holds the hostname and the NIS domain name, which makes it the easiest one to
+// see working: the child changes both, and ours stay the same.
+
+use crate::error_exit;
+use libc::c_char;
+use std::ffi::CStr;
+use std::mem;
+
+pub const DOMAINNAME: &str = "advent.example";
+
+fn field(value: &[c_char]) -> String {
+    unsafe { CStr::from_ptr(value.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn report(who: &str) {
+    let mut name: libc::utsname = unsafe { mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } == -1 {
+        error_exit("uname");
+    }
+    println!(
+        "{}: hostname {}, domainname {}",
+        who,
+        field(&name.nodename),
+        field(&name.domainname)
+    );
+}
+
+// Runs in the child once it's mapped to root in its user namespace, which gives it CAP_SYS_ADMIN over
+// the uts namespace that came with it
+pub unsafe fn change(hostname: &str) {
+    report("Child before");
+    if libc::sethostname(hostname.as_ptr() as *const c_char, hostname.len()) == -1 {
+        error_exit("sethostname");
+    }
+    if libc::setdomainname(DOMAINNAME.as_ptr() as *const c_char, DOMAINNAME.len()) == -1 {
+        error_exit("setdomainname");
+    }
+    report("Child after");
+}
@@ -1,237 +1,1041 @@
-use libc::{c_int, c_void, pid_t};
+use advent_2::arena::{ProcessShared, SharedArena};
+use advent_2::robust::{self, RobustMutex, RobustMutexGuard};
+use advent_2::sync::{Condvar, Mutex, Sem};
+use advent_2::thread;
+use libc::pid_t;
 use std::{
-    mem::size_of,
-    ptr,
-    sync::atomic::{AtomicU32, Ordering},
+    env,
+    mem::{align_of, size_of, MaybeUninit},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-// futex wrappers because they aren't in libc
-// todo: how much of this is unsafe? Could these wrappers be safe functions?
-unsafe fn futex_wake(addr: *const AtomicU32, nr: c_int) -> i64 {
-    libc::syscall(
-        libc::SYS_futex,
-        addr,
-        libc::FUTEX_WAKE,
-        nr,
-        libc::PT_NULL,
-        libc::PT_NULL,
-        0u32,
-    )
-}
-
-unsafe fn futex_wait(addr: *const AtomicU32, val: c_int) -> i64 {
-    libc::syscall(
-        libc::SYS_futex,
-        addr,
-        libc::FUTEX_WAIT,
-        val,
-        libc::PT_NULL,
-        libc::PT_NULL,
-        0u32,
-    )
-}
-
-// Task 1: Implement Semaphore
-struct Sem(AtomicU32);
-
-impl Sem {
-    // Initialize the semaphore with a given value
-    pub fn new(init: u32) -> Self {
-        Sem(AtomicU32::new(init))
-    }
-
-    // Try to decrement the semaphore. If it's larger than 0, just decrement.
-    // Otherwise, sleep until it's larger than 0 and then try decrementing it.
-    pub fn down(&mut self) {
-        // Use a loop because there could be a race
-        loop {
-            // todo: How strong an ordering guarantee do we actually need here?
-            let val = self.0.load(Ordering::Acquire);
-            if val > 0 {
-                if self
-                    .0
-                    .compare_exchange(val, val - 1, Ordering::AcqRel, Ordering::Acquire)
-                    == Ok(val)
-                {
-                    // Compare and exchange succeeded
-                    break;
-                }
-            } else {
-                unsafe {
-                    futex_wait(&self.0, 0);
-                }
-            }
-        }
-    }
-
-    // Increment the counter and wake one waiting thread
-    pub fn up(&mut self) {
-        // Increment the semaphore unconditionally
-        let prev = self.0.fetch_add(1, Ordering::AcqRel);
-
-        // If the old value was zero, somebody might be waiting. Therefore wake them up.
-        if prev == 0 {
-            unsafe {
-                futex_wake(&self.0, 1);
-            }
-        }
-    }
-}
+// Task 1: Implement Semaphore, which lives in the library as advent_2::sync::Sem now
 
 // Task 2: Implement Bounded Buffer
 
+// The capacity when none is given
 const ARRAY_SIZE: usize = 3;
 
-struct BoundedBuffer<T> {
+struct BoundedBuffer<T, const N: usize = ARRAY_SIZE> {
     // Two semaphores for the number of empty slots and the number of valid elements
     slots: Sem,
     elements: Sem,
 
-    // Another semaphore as a binary semaphore for synchronizing access to data and metadata
-    lock: Sem,
+    // The data and metadata, which only the holder of the lock can touch. The lock is a robust one, so
+    // a process that dies holding it doesn't leave everyone else waiting for it forever. That takes
+    // over the robust list of every thread that uses the buffer, so robust::enable has to be called
+    // first. Nothing here uses robust pthread mutexes, which would lose theirs.
+    ring: RobustMutex<Locked<T, N>>,
+}
 
-    read_idx: usize,  // next slot to read
-    write_idx: usize, // next slot to write
+struct Locked<T, const N: usize> {
+    ring: Ring<T, N>,
+    pending: Pending,
+}
 
-    data: [Option<T>; ARRAY_SIZE],
+// What the holder of the lock is in the middle of, and the count it started from, so that whoever gets
+// the lock after it died can tell how far it got
+#[derive(Clone, Copy)]
+enum Pending {
+    Nothing,
+    Put(usize),
+    Get(usize),
 }
 
-// Restricting to copyable values because it's a headache trying to do this otherwise and that's not really the point
-impl<T: Copy> BoundedBuffer<T> {
+struct Ring<T, const N: usize> {
+    // How many elements have ever been read and written. A push or pop only changes one of them, as
+    // the last thing it does, so one that's cut short leaves the ring as it was. They'd take centuries
+    // to wrap around.
+    reads: usize,
+    writes: usize,
+
+    // Only the slots between the two are initialized
+    data: [MaybeUninit<T>; N],
+}
+
+impl<T, const N: usize> Ring<T, N> {
+    fn new() -> Self {
+        // Caught at compile time, for every N a buffer gets made with
+        const { assert!(N > 0, "a buffer needs room for at least one element") };
+        Ring {
+            reads: 0,
+            writes: 0,
+
+            data: [const { MaybeUninit::uninit() }; N],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.writes.wrapping_sub(self.reads)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+
+        // The slot counts as in use until reads moves past it, and won't be read again before it's
+        // written
+        let val = unsafe { self.data[self.reads % N].assume_init_read() };
+        self.reads = self.reads.wrapping_add(1);
+        Some(val)
+    }
+
+    fn push(&mut self, val: T) {
+        assert!(!self.is_full(), "pushed onto a full ring");
+        self.data[self.writes % N].write(val);
+        self.writes = self.writes.wrapping_add(1);
+    }
+}
+
+// MaybeUninit never drops what's in it, so whatever is still in the ring is up to us
+impl<T, const N: usize> Drop for Ring<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+// Nothing in the buffers but their elements, locks and counters, so they can go wherever those can
+unsafe impl<T: ProcessShared, const N: usize> ProcessShared for Ring<T, N> {}
+unsafe impl<T: ProcessShared, const N: usize> ProcessShared for Locked<T, N> {}
+unsafe impl<T: ProcessShared, const N: usize> ProcessShared for BoundedBuffer<T, N> {}
+unsafe impl<T: ProcessShared, const N: usize> ProcessShared for CondvarBuffer<T, N> {}
+
+// What the benchmark needs from either kind of buffer
+trait Buffer<T> {
+    fn get(&self) -> T;
+    fn put(&self, val: T);
+}
+
+// The elements can end up in another thread, so they have to be allowed to go there. Between
+// processes it's more than that: nothing in them can point outside the shared memory, which is what
+// ProcessShared is for.
+impl<T: Send, const N: usize> BoundedBuffer<T, N> {
     pub fn new() -> Self {
         BoundedBuffer {
-            // Count number of empty slots (initially ARRAY_SIZE) and valid elements (initially 0)
-            slots: Sem::new(
-                ARRAY_SIZE
-                    .try_into()
-                    .expect("could not convert usize to u32"),
-            ),
+            // Count number of empty slots (initially N) and valid elements (initially 0)
+            slots: Sem::new(N.try_into().expect("could not convert usize to u32")),
             elements: Sem::new(0),
 
-            // 1 means the mutex is free
-            lock: Sem::new(1),
+            ring: RobustMutex::new(Locked {
+                ring: Ring::new(),
+                pending: Pending::Nothing,
+            }),
+        }
+    }
 
-            // Start reading and writing at 0
-            read_idx: 0,
-            write_idx: 0,
+    // Lock the ring, first putting things right if the last one to have it died holding it
+    fn lock(&self) -> RobustMutexGuard<'_, Locked<T, N>> {
+        match self.ring.lock() {
+            Ok(locked) => locked,
+            Err(died) => {
+                let mut locked = died.into_inner();
+                self.repair(&mut locked);
+                locked
+            }
+        }
+    }
+
+    // A put or get takes one semaphore before it locks the ring and ups the other once it's done with
+    // it. The ring is fine either way, so all that's left to do is whichever of the two it didn't get
+    // to. A process that dies outside the lock, or right between finishing with the ring and the up,
+    // still takes its count with it.
+    fn repair(&self, locked: &mut Locked<T, N>) {
+        let ring = &locked.ring;
+        match locked.pending {
+            Pending::Nothing => {}
+
+            // Give back what it took
+            Pending::Put(writes) if ring.writes == writes => self.slots.up(),
+            Pending::Get(reads) if ring.reads == reads => self.elements.up(),
 
-            data: [None; ARRAY_SIZE],
+            // Or pass on what it made
+            Pending::Put(_) => self.elements.up(),
+            Pending::Get(_) => self.slots.up(),
         }
+        locked.pending = Pending::Nothing;
     }
 
     // blocks until there is an item to get
-    pub fn get(&mut self) -> T {
+    pub fn get(&self) -> T {
         // Ensure there is an element to get
         self.elements.down();
+        self.take()
+    }
 
-        let ret: Option<T>;
+    pub fn try_get(&self) -> Option<T> {
+        self.elements.try_down().then(|| self.take())
+    }
 
-        // Critical section, protected by lock
-        {
-            self.lock.down();
+    pub fn get_timeout(&self, timeout: Duration) -> Option<T> {
+        self.elements.down_timeout(timeout).then(|| self.take())
+    }
 
-            ret = self.data[self.read_idx].take();
-            self.read_idx = (self.read_idx + 1) % ARRAY_SIZE;
+    // Once we have an element to ourselves
+    fn take(&self) -> T {
+        self.take_any().unwrap()
+    }
 
-            self.lock.up();
-        }
+    // The same, except that what we have to ourselves may be a close, see Channel
+    fn take_any(&self) -> Option<T> {
+        // Critical section, while the guard is around
+        let mut locked = self.lock();
+        locked.pending = Pending::Get(locked.ring.reads);
+        let ret = locked.ring.pop();
+        locked.pending = Pending::Nothing;
+        drop(locked);
 
         // More slots are now empty, so increase the slots semaphore which may wake other threads
-        self.slots.up();
-
-        ret.unwrap()
+        if ret.is_some() {
+            self.slots.up();
+        }
+        ret
     }
 
-    pub fn put(&mut self, val: T) {
+    pub fn put(&self, val: T) {
         // Ensure there is space for the element
         self.slots.down();
+        self.insert(val);
+    }
 
-        // Critical section
-        {
-            self.lock.down();
+    // Hands the value back if there's no room for it
+    pub fn try_put(&self, val: T) -> Result<(), T> {
+        if !self.slots.try_down() {
+            return Err(val);
+        }
+        self.insert(val);
+        Ok(())
+    }
 
-            self.data[self.write_idx] = Some(val);
-            self.write_idx = (self.write_idx + 1) % ARRAY_SIZE;
+    pub fn put_timeout(&self, val: T, timeout: Duration) -> Result<(), T> {
+        if !self.slots.down_timeout(timeout) {
+            return Err(val);
+        }
+        self.insert(val);
+        Ok(())
+    }
+
+    // Once we have a slot to ourselves
+    fn insert(&self, val: T) {
+        self.insert_if(val, || true).ok();
+    }
 
-            self.lock.up();
+    // The same, except that the slot and the value get handed back if `open` says no. It gets asked
+    // with the ring locked.
+    fn insert_if(&self, val: T, open: impl FnOnce() -> bool) -> Result<(), T> {
+        // Critical section
+        let mut locked = self.lock();
+        if !open() {
+            drop(locked);
+            self.slots.up();
+            return Err(val);
         }
+        locked.pending = Pending::Put(locked.ring.writes);
+        locked.ring.push(val);
+        locked.pending = Pending::Nothing;
+        drop(locked);
 
         self.elements.up();
+        Ok(())
+    }
+
+    // Only a snapshot, other processes or threads can change it right after
+    pub fn len(&self) -> usize {
+        self.lock().ring.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().ring.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.lock().ring.is_full()
+    }
+}
+
+impl<T: Send, const N: usize> Buffer<T> for BoundedBuffer<T, N> {
+    fn get(&self) -> T {
+        BoundedBuffer::get(self)
+    }
+
+    fn put(&self, val: T) {
+        BoundedBuffer::put(self, val)
+    }
+}
+
+// The same buffer with one mutex and two condition variables. The ring itself tells whether there's
+// anything to get or room to put, so there are no semaphores to keep in step with it.
+struct CondvarBuffer<T, const N: usize = ARRAY_SIZE> {
+    ring: Mutex<Ring<T, N>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+impl<T: Send, const N: usize> CondvarBuffer<T, N> {
+    pub fn new() -> Self {
+        CondvarBuffer {
+            ring: Mutex::new(Ring::new()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    pub fn get(&self) -> T {
+        let ret = self
+            .not_empty
+            .wait_while(self.ring.lock(), |ring| ring.is_empty())
+            .pop();
+
+        // Notifying after the guard is gone saves the woken putter from going straight back to sleep
+        // on the mutex
+        self.not_full.notify_one();
+
+        ret.unwrap()
+    }
+
+    pub fn put(&self, val: T) {
+        self.not_full
+            .wait_while(self.ring.lock(), |ring| ring.is_full())
+            .push(val);
+        self.not_empty.notify_one();
+    }
+}
+
+impl<T: Send, const N: usize> Buffer<T> for CondvarBuffer<T, N> {
+    fn get(&self) -> T {
+        CondvarBuffer::get(self)
+    }
+
+    fn put(&self, val: T) {
+        CondvarBuffer::put(self, val)
+    }
+}
+
+// A channel around the buffer, so the receiving side finds out when there's nothing more coming
+// instead of having to know what the last value is. It's closed explicitly or when the last sender
+// goes away, after which receivers get what's left and then None. Once the last receiver is gone,
+// sending fails instead of blocking forever on a buffer nobody empties. Values sent before the first
+// receiver turns up just wait in the buffer.
+//
+// The channel itself goes wherever the buffer would, shared memory included. How many senders and
+// receivers there will be is given up front, and each of them claims its handle wherever it runs,
+// after a fork for example. Counting them as they turned up instead would let a process that's slow
+// to start find the channel closed or disconnected by the ones that were already done, and a handle
+// made before the fork would be copied and dropped twice.
+struct Channel<T, const N: usize = ARRAY_SIZE> {
+    buf: BoundedBuffer<T, N>,
+
+    // Handles not dropped yet, claimed or not
+    senders: AtomicU32,
+    receivers: AtomicU32,
+
+    // Handles given to new that nobody has claimed yet. One claimed too many would be dropped too,
+    // and take the count below the handles that are still around.
+    unclaimed_senders: AtomicU32,
+    unclaimed_receivers: AtomicU32,
+
+    // Only changed with the ring locked, so a value is either in before the close or not at all
+    closed: AtomicBool,
+}
+
+unsafe impl<T: ProcessShared, const N: usize> ProcessShared for Channel<T, N> {}
+
+struct Sender<'a, T: Send, const N: usize = ARRAY_SIZE> {
+    chan: &'a Channel<T, N>,
+}
+
+struct Receiver<'a, T: Send, const N: usize = ARRAY_SIZE> {
+    chan: &'a Channel<T, N>,
+}
+
+// The value that couldn't be sent, handed back
+#[derive(Debug)]
+struct SendError<T>(T);
+
+impl<T: Send, const N: usize> Channel<T, N> {
+    pub fn new(senders: u32, receivers: u32) -> Self {
+        let chan = Channel {
+            buf: BoundedBuffer::new(),
+            senders: AtomicU32::new(senders),
+            receivers: AtomicU32::new(receivers),
+            unclaimed_senders: AtomicU32::new(senders),
+            unclaimed_receivers: AtomicU32::new(receivers),
+            closed: AtomicBool::new(false),
+        };
+        if senders == 0 {
+            chan.close();
+        }
+        chan
+    }
+
+    // Claim one of the senders given to new. Panics once they've all been claimed.
+    pub fn sender(&self) -> Sender<'_, T, N> {
+        claim(&self.unclaimed_senders, "senders");
+        Sender { chan: self }
+    }
+
+    // Claim one of the receivers given to new, just like a sender
+    pub fn receiver(&self) -> Receiver<'_, T, N> {
+        claim(&self.unclaimed_receivers, "receivers");
+        Receiver { chan: self }
+    }
+
+    fn close(&self) {
+        let _locked = self.buf.lock();
+        if !self.closed.swap(true, Ordering::SeqCst) {
+            // One extra element that isn't there. Whoever gets it finds the ring empty and passes it
+            // on, so every receiver blocked now or later wakes up to None.
+            self.buf.elements.up();
+        }
+    }
+
+    fn disconnected(&self) -> bool {
+        self.closed.load(Ordering::SeqCst) || self.receivers.load(Ordering::SeqCst) == 0
+    }
+}
+
+fn claim(unclaimed: &AtomicU32, what: &str) {
+    if unclaimed
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_err()
+    {
+        panic!("more {} claimed than the channel was made for", what);
+    }
+}
+
+impl<T: Send, const N: usize> Sender<'_, T, N> {
+    pub fn send(&self, val: T) -> Result<(), SendError<T>> {
+        let chan = self.chan;
+        if chan.disconnected() {
+            return Err(SendError(val));
+        }
+
+        // If it's not used after all, the slot goes back, which is also how the next sender waiting for
+        // one finds out
+        chan.buf.slots.down();
+        chan.buf
+            .insert_if(val, || !chan.disconnected())
+            .map_err(SendError)
+    }
+
+    // Closes it for every sender, not just this one
+    pub fn close(&self) {
+        self.chan.close();
+    }
+}
+
+// A handle of our own, so the count can't be 0 while it goes up. The clone is an extra handle rather
+// than one of the unclaimed ones.
+impl<T: Send, const N: usize> Clone for Sender<'_, T, N> {
+    fn clone(&self) -> Self {
+        self.chan.senders.fetch_add(1, Ordering::SeqCst);
+        Sender { chan: self.chan }
+    }
+}
+
+impl<T: Send, const N: usize> Drop for Sender<'_, T, N> {
+    fn drop(&mut self) {
+        if self.chan.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.chan.close();
+        }
+    }
+}
+
+impl<T: Send, const N: usize> Receiver<'_, T, N> {
+    // Blocks until there's a value, or None once the channel is closed and empty
+    pub fn recv(&self) -> Option<T> {
+        let buf = &self.chan.buf;
+        buf.elements.down();
+        let val = buf.take_any();
+        if val.is_none() {
+            // That was the close, leave it for the next receiver
+            buf.elements.up();
+        }
+        val
+    }
+}
+
+impl<T: Send, const N: usize> Iterator for Receiver<'_, T, N> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.recv()
+    }
+}
+
+impl<T: Send, const N: usize> Clone for Receiver<'_, T, N> {
+    fn clone(&self) -> Self {
+        self.chan.receivers.fetch_add(1, Ordering::SeqCst);
+        Receiver { chan: self.chan }
+    }
+}
+
+impl<T: Send, const N: usize> Drop for Receiver<'_, T, N> {
+    fn drop(&mut self) {
+        if self.chan.receivers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // Wake a sender waiting for a slot, which passes it on like the receivers do with a close.
+            // That leaves one slot too many for good, but nothing gets sent anymore to fill it.
+            self.chan.buf.slots.up();
+        }
+    }
+}
+
+// Pass `count` items through a buffer in shared memory from producer processes to consumer processes,
+// and time how long it takes
+fn bench_one<B: Buffer<u32> + ProcessShared>(
+    buf: B,
+    producers: usize,
+    consumers: usize,
+    count: usize,
+) -> Duration {
+    // Room for the buffer after the arena's own offset, however far it has to move to be aligned
+    let size = size_of::<AtomicUsize>() + align_of::<B>() + size_of::<B>();
+    let arena = SharedArena::new(size).expect("could not map an arena");
+    let buf = arena.alloc(buf);
+
+    let start = Instant::now();
+
+    // Each process gets its share of the items, with the first ones doing any that are left over
+    let share = |i: usize, n: usize| count / n + usize::from(i < count % n);
+    let mut children = Vec::new();
+    for i in 0..producers {
+        children.push(fork_with(|| {
+            for n in 0..share(i, producers) {
+                buf.put(n as u32);
+            }
+        }));
+    }
+    for i in 0..consumers {
+        children.push(fork_with(|| {
+            for _ in 0..share(i, consumers) {
+                buf.get();
+            }
+        }));
+    }
+    for child in children {
+        let mut status = 0;
+        if unsafe { libc::waitpid(child, &mut status, 0) } == -1 {
+            panic!("Could not wait for {}", child);
+        }
+    }
+
+    start.elapsed()
+}
+
+fn fork_with(f: impl FnOnce()) -> pid_t {
+    match unsafe { libc::fork() } {
+        -1 => panic!("Could not fork"),
+        0 => {
+            f();
+            unsafe { libc::_exit(0) }
+        }
+        pid => pid,
+    }
+}
+
+fn bench(count: usize) {
+    println!(
+        "Passing {} items through a buffer of {}, producers and consumers in processes of their own",
+        count, ARRAY_SIZE
+    );
+    println!(
+        "{:<12} {:>10} {:>12} {:>12}",
+        "buffer", "processes", "total ms", "ns per item"
+    );
+
+    for (producers, consumers) in [(1, 1), (4, 4)] {
+        let times = [
+            (
+                "semaphores",
+                bench_one(BoundedBuffer::<u32>::new(), producers, consumers, count),
+            ),
+            (
+                "condvars",
+                bench_one(CondvarBuffer::<u32>::new(), producers, consumers, count),
+            ),
+        ];
+        for (name, time) in times {
+            println!(
+                "{:<12} {:>10} {:>12.1} {:>12.1}",
+                name,
+                format!("{}+{}", producers, consumers),
+                time.as_secs_f64() * 1e3,
+                time.as_secs_f64() * 1e9 / count as f64
+            );
+        }
     }
 }
 
 // Task 3: Use the Bounded Buffer
 
-fn main() {
-    let child: pid_t;
-    let ready: &mut Sem;
-    let buf: &mut BoundedBuffer<u32>;
-
-    // Make a shared region of memory for the buffer
-    unsafe {
-        let shared_mem = libc::mmap(
-            libc::PT_NULL as *mut c_void,
-            4096,
-            libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_ANONYMOUS | libc::MAP_SHARED,
-            -1,
-            0,
-        );
-        if shared_mem == libc::MAP_FAILED {
-            panic!("Could not mmap");
+// Between threads the elements can be anything that may be sent to another thread, including things
+// that live on the heap. Whatever is still in the buffer gets dropped along with it.
+fn strings_between_threads() {
+    let buf = Arc::new(BoundedBuffer::<String, 2>::new());
+
+    let consumer = {
+        let buf = buf.clone();
+        thread::spawn(move || (0..3).map(|_| buf.get()).collect::<Vec<_>>())
+            .expect("could not spawn thread")
+    };
+    for word in ["one", "two", "three", "four", "five"] {
+        buf.put(word.to_string());
+    }
+
+    println!(
+        "Threads: Got {:?}, leaving {} in the buffer to be dropped.",
+        consumer.join().unwrap(),
+        buf.len()
+    );
+}
+
+// Any number of producers and consumers can share a channel. The consumers stop once the producers
+// are all gone and everything they sent has been taken.
+fn channel_between_threads() {
+    let chan = Channel::<String, 2>::new(3, 2);
+
+    let taken: Vec<Vec<String>> = std::thread::scope(|scope| {
+        for producer in 0..3 {
+            let tx = chan.sender();
+            scope.spawn(move || {
+                for n in 0..4 {
+                    tx.send(format!("{}.{}", producer, n)).unwrap();
+                }
+            });
         }
+        let consumers: Vec<_> = (0..2)
+            .map(|_| {
+                let rx = chan.receiver();
+                scope.spawn(move || rx.collect())
+            })
+            .collect();
+        consumers.into_iter().map(|c| c.join().unwrap()).collect()
+    });
+    for (i, values) in taken.iter().enumerate() {
+        println!("Threads: Consumer {} got {:?}", i, values);
+    }
 
-        let ready_location = shared_mem;
-        ptr::write(ready_location as *mut Sem, Sem::new(0));
-        ready = &mut *(ready_location as *mut Sem);
+    // Nobody is left to receive
+    let chan = Channel::<String, 2>::new(1, 1);
+    let tx = chan.sender();
+    drop(chan.receiver());
+    if let Err(SendError(val)) = tx.send("late".to_string()) {
+        println!("Threads: Couldn't send {:?} without receivers.", val);
+    }
+}
 
-        // The correct way to do this would probably be to have the semaphore wrapped up with the buffer in a struct or something.
-        // As it is right now it's really bad because rust doesn't know that the reference isn't actually initialized.
-        let buf_location = shared_mem.add(size_of::<Sem>());
-        buf = &mut *(buf_location as *mut BoundedBuffer<u32>);
+fn main() {
+    robust::enable();
 
-        // Fork to test the synchronization
-        child = libc::fork();
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("bench") {
+        // At least one item, or there's nothing to time it per
+        let count = match args.get(2) {
+            Some(count) => count
+                .parse()
+                .ok()
+                .filter(|&count| count > 0)
+                .unwrap_or_else(|| {
+                    eprintln!("usage: futex [bench [<count>]], with a count of at least 1");
+                    process::exit(1);
+                }),
+            None => 100_000,
+        };
+        bench(count);
+        return;
     }
 
+    // Everything the two processes share goes in here before the fork, so it's at the same place in
+    // both of them
+    let arena = SharedArena::new(4096).unwrap_or_else(|err| {
+        println!("Error in mmap: {:?}", err);
+        process::exit(1);
+    });
+    let chan = arena.alloc(Channel::<u32>::new(1, 1));
+
+    // Fork to test the synchronization
+    let child = unsafe { libc::fork() };
+
     if child != 0 {
         // Parent
 
-        println!("Parent: Waiting for child to initialize buffer...");
+        println!("Parent: Reading from buffer...");
 
-        ready.down();
+        let rx = chan.receiver();
 
-        println!("Parent: Child has initialized buffer. Reading from buffer...");
-
-        loop {
-            let val = buf.get();
+        for val in rx {
             println!("Parent: {}", val);
-            if val == 5 {
-                break;
-            }
         }
+        println!("Parent: Child closed the channel.");
+
+        // A buffer of our own, to try the calls that don't wait, or not for long
+        let buf = BoundedBuffer::<u32>::new();
+        if buf.get_timeout(Duration::from_millis(100)).is_none() {
+            println!("Parent: Nothing after 100ms.");
+        }
+        if buf.try_get().is_none() {
+            println!("Parent: Nothing left, is_empty says {}.", buf.is_empty());
+        }
+        let mut n = 0;
+        while buf.try_put(n).is_ok() {
+            n += 1;
+        }
+        println!(
+            "Parent: Buffer is full after {} more, holding {} of {}.",
+            n,
+            buf.len(),
+            buf.capacity()
+        );
+        if let Err(val) = buf.put_timeout(n, Duration::from_millis(100)) {
+            println!(
+                "Parent: Gave up putting {} after 100ms, is_full says {}.",
+                val,
+                buf.is_full()
+            );
+        }
+
+        strings_between_threads();
+        channel_between_threads();
     } else {
         // Child
 
-        sleep(Duration::from_secs(1));
+        let tx = chan.sender();
 
-        println!("Child: Initializing buffer...");
+        println!("Child: Writing to buffer...");
 
-        unsafe {
-            ptr::write(buf as *mut BoundedBuffer<u32>, BoundedBuffer::<u32>::new());
+        for n in 1..6 {
+            sleep(Duration::from_secs(1));
+            tx.send(n).unwrap();
         }
+        tx.close();
+    }
+}
 
-        println!("Child: Initialized buffer.");
+#[cfg(test)]
+mod tests {
+    use super::{fork_with, BoundedBuffer, Channel, Locked, Pending};
+    use advent_2::arena::SharedArena;
+    use advent_2::robust;
+    use advent_2::sync::Sem;
+    use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread::{scope, sleep};
+    use std::time::Duration;
 
-        ready.up();
+    // Everything here locks a RobustMutex, which needs robust::enable first, so they're all made here
+    fn buffer<T: Send, const N: usize>() -> BoundedBuffer<T, N> {
+        robust::enable();
+        BoundedBuffer::new()
+    }
 
-        println!("Child: Writing to buffer...");
+    fn channel<T: Send, const N: usize>(senders: u32, receivers: u32) -> Channel<T, N> {
+        robust::enable();
+        Channel::new(senders, receivers)
+    }
 
-        for n in 1..6 {
-            sleep(Duration::from_secs(1));
-            buf.put(n);
+    // Counts how many of it have been dropped
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn leftovers_get_dropped_once() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let buf = buffer::<Counted, 3>();
+        for _ in 0..3 {
+            buf.put(Counted(drops.clone()));
+        }
+
+        // Taken out and dropped by us, then the two still in there by the buffer
+        drop(buf.get());
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        drop(buf);
+        assert_eq!(drops.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn strings_wrap_around_in_order() {
+        let buf = buffer::<String, 2>();
+        let mut next = 0;
+        for round in 0..10 {
+            // Alternating between one and two at a time, so the ring wraps at different places
+            let batch = 1 + round % 2;
+            for i in 0..batch {
+                buf.put(format!("value {}", next + i));
+            }
+            for _ in 0..batch {
+                assert_eq!(buf.get(), format!("value {}", next));
+                next += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn sizes_follow_the_contents() {
+        let buf = buffer::<u32, 2>();
+        assert_eq!((buf.len(), buf.capacity()), (0, 2));
+        assert!(buf.is_empty() && !buf.is_full());
+
+        buf.put(1);
+        assert_eq!(buf.len(), 1);
+        assert!(!buf.is_empty() && !buf.is_full());
+
+        buf.put(2);
+        assert_eq!(buf.len(), 2);
+        assert!(!buf.is_empty() && buf.is_full());
+        assert_eq!(buf.try_put(3), Err(3));
+
+        assert_eq!(buf.get(), 1);
+        assert_eq!(buf.len(), 1);
+        assert!(!buf.is_full());
+    }
+
+    #[test]
+    fn none_after_the_last_value() {
+        let chan = channel::<u32, 2>(1, 1);
+        let tx = chan.sender();
+
+        // Nobody's listening yet, which is fine as long as there's room
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        drop(tx);
+
+        let rx = chan.receiver();
+        assert_eq!(rx.recv(), Some(1));
+        assert_eq!(rx.recv(), Some(2));
+        assert_eq!(rx.recv(), None);
+        assert_eq!(rx.clone().recv(), None);
+    }
+
+    #[test]
+    fn sending_fails_once_the_receivers_are_gone() {
+        let chan = channel::<u32, 1>(1, 1);
+        let tx = chan.sender();
+        let rx = chan.receiver();
+        tx.send(1).unwrap();
+
+        // The ring is full, so this one is asleep waiting for a slot when the receiver goes
+        let sent = scope(|scope| {
+            let blocked = scope.spawn(|| tx.send(2));
+            sleep(Duration::from_millis(50));
+            drop(rx);
+            blocked.join().unwrap()
+        });
+        assert_eq!(sent.unwrap_err().0, 2);
+        assert_eq!(tx.send(3).unwrap_err().0, 3);
+    }
+
+    // The slot that woke the senders up can't be used to overfill the ring
+    #[test]
+    fn nothing_goes_in_after_the_last_receiver() {
+        let chan = channel::<u32, 1>(1, 1);
+        let tx = chan.sender();
+        drop(chan.receiver());
+
+        for n in 0..3 {
+            assert!(tx.send(n).is_err());
+        }
+        assert_eq!(chan.buf.len(), 0);
+    }
+
+    // One of each is done before the other has even turned up, which mustn't close or disconnect the
+    // channel for the late ones
+    #[test]
+    fn late_handles_still_count() {
+        let chan = channel::<u32, 2>(2, 2);
+        drop(chan.receiver());
+        let tx = chan.sender();
+        tx.send(1).unwrap();
+        drop(tx);
+
+        let tx = chan.sender();
+        tx.send(2).unwrap();
+        drop(tx);
+        let rx = chan.receiver();
+        assert_eq!(rx.collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "more senders claimed than the channel was made for")]
+    fn claiming_too_many_panics() {
+        let chan = channel::<u32, 2>(1, 1);
+        let _tx = chan.sender();
+        let _also = chan.sender();
+    }
+
+    #[test]
+    fn no_senders_means_closed() {
+        let chan = channel::<u32, 2>(0, 1);
+        assert_eq!(chan.receiver().recv(), None);
+    }
+
+    #[test]
+    fn many_to_many() {
+        let chan = channel::<usize, 3>(4, 3);
+        let mut taken: Vec<usize> = scope(|scope| {
+            for producer in 0..4 {
+                let tx = chan.sender();
+                scope.spawn(move || {
+                    for n in 0..250 {
+                        tx.send(producer * 250 + n).unwrap();
+                    }
+                });
+            }
+            let consumers: Vec<_> = (0..3)
+                .map(|_| {
+                    let rx = chan.receiver();
+                    scope.spawn(move || {
+                        let mut taken = Vec::new();
+                        for v in rx {
+                            taken.push(v);
+                        }
+                        taken
+                    })
+                })
+                .collect();
+            consumers
+                .into_iter()
+                .flat_map(|c| c.join().unwrap())
+                .collect()
+        });
+        taken.sort();
+        assert_eq!(taken, (0..1000).collect::<Vec<_>>());
+    }
+
+    // The same between processes, with everyone claiming their handle after the fork and some of them
+    // taking their time about it
+    #[test]
+    fn many_to_many_processes() {
+        const PRODUCERS: usize = 4;
+        const CONSUMERS: usize = 3;
+        const EACH: usize = 250;
+
+        let arena = SharedArena::new(16384).unwrap();
+        let chan = arena.alloc(channel::<u32, 3>(PRODUCERS as u32, CONSUMERS as u32));
+        let seen = arena.alloc([const { AtomicU32::new(0) }; PRODUCERS * EACH]);
+
+        let mut children = Vec::new();
+        for producer in 0..PRODUCERS {
+            children.push(fork_with(|| {
+                if producer == 0 {
+                    sleep(Duration::from_millis(50));
+                }
+                let tx = chan.sender();
+                for n in 0..EACH {
+                    tx.send((producer * EACH + n) as u32).unwrap();
+                }
+            }));
+        }
+        for consumer in 0..CONSUMERS {
+            children.push(fork_with(|| {
+                if consumer == 0 {
+                    sleep(Duration::from_millis(100));
+                }
+                for v in chan.receiver() {
+                    seen[v as usize].fetch_add(1, Ordering::SeqCst);
+                }
+            }));
+        }
+
+        for child in children {
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+            assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
         }
+        assert!(seen.iter().all(|n| n.load(Ordering::SeqCst) == 1));
+    }
+
+    // Start with one value in there, then have a child get as far as `step` with the lock held before
+    // it's killed. Returns the slots, elements and values once the next one to lock has fixed it up.
+    fn kill_holding_the_lock(
+        put: bool,
+        step: impl FnOnce(&mut Locked<u32, 3>),
+    ) -> (u32, u32, Vec<u32>) {
+        let arena = SharedArena::new(4096).unwrap();
+        let buf = arena.alloc(buffer::<u32, 3>());
+        let locked = arena.alloc(Sem::new(0));
+        buf.put(1);
+
+        let child = fork_with(|| {
+            if put {
+                buf.slots.down();
+            } else {
+                buf.elements.down();
+            }
+            let mut guard = buf.lock();
+            step(&mut guard);
+            locked.up();
+            loop {
+                unsafe { libc::pause() };
+            }
+        });
+        locked.down();
+        unsafe {
+            libc::kill(child, libc::SIGKILL);
+            libc::waitpid(child, std::ptr::null_mut(), 0);
+        }
+
+        // The first lock after the kill does the repair, so it comes before reading the counts
+        let len = buf.len();
+        let counts = (buf.slots.value(), buf.elements.value());
+        let values: Vec<u32> = (0..len).map(|_| buf.try_get().unwrap()).collect();
+        assert_eq!(buf.try_get(), None);
+        (counts.0, counts.1, values)
+    }
+
+    #[test]
+    fn killed_before_putting_gives_the_slot_back() {
+        let after = kill_holding_the_lock(true, |locked| {
+            locked.pending = Pending::Put(locked.ring.writes);
+        });
+        assert_eq!(after, (2, 1, vec![1]));
+    }
+
+    #[test]
+    fn killed_after_putting_passes_the_element_on() {
+        let after = kill_holding_the_lock(true, |locked| {
+            locked.pending = Pending::Put(locked.ring.writes);
+            locked.ring.push(2);
+        });
+        assert_eq!(after, (1, 2, vec![1, 2]));
+    }
+
+    #[test]
+    fn killed_before_getting_gives_the_element_back() {
+        let after = kill_holding_the_lock(false, |locked| {
+            locked.pending = Pending::Get(locked.ring.reads);
+        });
+        assert_eq!(after, (2, 1, vec![1]));
+    }
+
+    #[test]
+    fn killed_after_getting_passes_the_slot_on() {
+        let after = kill_holding_the_lock(false, |locked| {
+            locked.pending = Pending::Get(locked.ring.reads);
+            locked.ring.pop();
+        });
+        assert_eq!(after, (3, 0, vec![]));
     }
 }
@@ -0,0 +1,130 @@
+// A cgroup v2 directory of our own, for putting limits on a child and seeing what it used. Everything
+// goes through files: limits are written to the controllers' interface files, processes are moved in
+// by writing their pid to cgroup.procs, and statistics are read back out.
+
+use libc::{c_int, pid_t};
+use std::ffi::CString;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Where the unified hierarchy is usually mounted. On systems that still mount the v1 hierarchies
+// there, it tends to be in /sys/fs/cgroup/unified instead.
+pub const DEFAULT_ROOT: &str = "/sys/fs/cgroup";
+
+pub struct Cgroup {
+    path: PathBuf,
+
+    // The directory itself, for CLONE_INTO_CGROUP
+    fd: c_int,
+}
+
+impl Cgroup {
+    // Create `name` under `root`, with the given controllers enabled for it. A controller's files only
+    // show up in a cgroup if its parent has the controller in cgroup.subtree_control.
+    pub fn create(root: &Path, name: &str, controllers: &[&str]) -> Result<Self> {
+        if !controllers.is_empty() {
+            let available = fs::read_to_string(root.join("cgroup.controllers"))
+                .map_err(|err| context(err, &root.join("cgroup.controllers")))?;
+            if let Some(missing) = controllers
+                .iter()
+                .find(|controller| !available.split_whitespace().any(|c| c == **controller))
+            {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!(
+                        "the {} controller isn't available in {} (maybe it's still bound to a v1 \
+                         hierarchy?)",
+                        missing,
+                        root.display()
+                    ),
+                ));
+            }
+
+            let enable: Vec<String> = controllers.iter().map(|c| format!("+{}", c)).collect();
+            let subtree_control = root.join("cgroup.subtree_control");
+            fs::write(&subtree_control, enable.join(" "))
+                .map_err(|err| context(err, &subtree_control))?;
+        }
+
+        let path = root.join(name);
+        fs::create_dir(&path).map_err(|err| context(err, &path))?;
+
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let fd = unsafe {
+            libc::open(
+                c_path.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            )
+        };
+        if fd == -1 {
+            let err = context(Error::last_os_error(), &path);
+            let _ = fs::remove_dir(&path);
+            return Err(err);
+        }
+
+        Ok(Cgroup { path, fd })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn as_raw(&self) -> c_int {
+        self.fd
+    }
+
+    // Write one of the interface files, like `set("pids.max", "10")`
+    pub fn set(&self, file: &str, value: &str) -> Result<()> {
+        let path = self.path.join(file);
+        fs::write(&path, value).map_err(|err| context(err, &path))
+    }
+
+    pub fn get(&self, file: &str) -> Result<String> {
+        let path = self.path.join(file);
+        fs::read_to_string(&path)
+            .map(|value| value.trim_end().to_string())
+            .map_err(|err| context(err, &path))
+    }
+
+    // Move a process in after the fact. Whatever it forks from then on stays in here too.
+    pub fn add(&self, pid: pid_t) -> Result<()> {
+        self.set("cgroup.procs", &pid.to_string())
+    }
+
+    // Whether there's any process left in here, including ones that were orphaned
+    pub fn populated(&self) -> bool {
+        self.get("cgroup.events")
+            .map(|events| events.lines().any(|line| line == "populated 1"))
+            .unwrap_or(false)
+    }
+
+    // SIGKILL everything in here at once. Unlike going through cgroup.procs, nothing can fork its way
+    // out of it.
+    pub fn kill(&self) -> Result<()> {
+        self.set("cgroup.kill", "1")
+    }
+}
+
+// A cgroup can only be removed once there's nobody left in it. The child should have been waited for
+// by now, but whatever it left behind (say, the rest of a fork bomb) gets killed first.
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+
+        if self.populated() && self.kill().is_ok() {
+            let start = Instant::now();
+            while self.populated() && start.elapsed() < Duration::from_secs(1) {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+fn context(err: Error, path: &Path) -> Error {
+    Error::new(err.kind(), format!("{}: {}", path.display(), err))
+}
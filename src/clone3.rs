@@ -0,0 +1,79 @@
+use crate::robust;
+use libc::{c_int, pid_t};
+use std::io::{Error, Result};
+use std::mem::size_of;
+
+// Start the child in the cgroup the fd in `cgroup` refers to, missing from libc
+pub const CLONE_INTO_CGROUP: u64 = 0x200000000;
+
+// struct clone_args from linux/sched.h. Every pointer is passed as a u64 so the layout is the same for
+// 32 and 64 bit processes.
+#[repr(C)]
+#[derive(Default)]
+pub struct CloneArgs {
+    pub flags: u64,
+
+    // Where to store the pidfd with CLONE_PIDFD
+    pub pidfd: u64,
+    pub child_tid: u64,
+    pub parent_tid: u64,
+
+    // Unlike clone, the signal sent to the parent on exit gets its own field instead of the low byte of flags
+    pub exit_signal: u64,
+    pub stack: u64,
+    pub stack_size: u64,
+    pub tls: u64,
+
+    // Array of pids to use for the child, one per nested pid namespace starting with the innermost
+    pub set_tid: u64,
+    pub set_tid_size: u64,
+
+    // cgroup directory fd for CLONE_INTO_CGROUP
+    pub cgroup: u64,
+}
+
+impl CloneArgs {
+    pub fn pidfd(mut self, pidfd: &mut c_int) -> Self {
+        self.flags |= libc::CLONE_PIDFD as u64;
+        self.pidfd = pidfd as *mut c_int as u64;
+        self
+    }
+
+    pub fn set_tid(mut self, tids: &[pid_t]) -> Self {
+        // The kernel insists on a null pointer when there aren't any
+        if !tids.is_empty() {
+            self.set_tid = tids.as_ptr() as u64;
+            self.set_tid_size = tids.len() as u64;
+        }
+        self
+    }
+
+    // Put the child straight into a cgroup, given an fd for its directory. Unlike moving it there
+    // afterwards, there's no window in which it runs outside of it.
+    pub fn cgroup(mut self, fd: c_int) -> Self {
+        self.flags |= CLONE_INTO_CGROUP;
+        self.cgroup = fd as u64;
+        self
+    }
+}
+
+/// Like fork, this returns twice: the child's pid in the parent and 0 in the child
+///
+/// # Safety
+///
+/// Everything pointed to by `args` has to be valid. Without a stack the child runs on a copy of the
+/// caller's, which only works without CLONE_VM.
+pub unsafe fn clone3(args: &CloneArgs) -> Result<pid_t> {
+    let ret = libc::syscall(
+        libc::SYS_clone3,
+        args as *const CloneArgs,
+        size_of::<CloneArgs>(),
+    );
+    if ret == -1 {
+        return Err(Error::last_os_error());
+    }
+    if ret == 0 {
+        robust::forget_registration();
+    }
+    Ok(ret as pid_t)
+}
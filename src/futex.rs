@@ -0,0 +1,136 @@
+use libc::{c_int, timespec};
+use std::sync::atomic::AtomicU32;
+use std::time::Instant;
+
+// futex wrappers because they aren't in libc
+// todo: how much of this is unsafe? Could these wrappers be safe functions?
+
+/// Wake up to `nr` waiters sleeping on `addr`
+///
+/// # Safety
+///
+/// `addr` has to point to a live futex word
+pub unsafe fn futex_wake(addr: *const AtomicU32, nr: c_int) -> i64 {
+    libc::syscall(
+        libc::SYS_futex,
+        addr,
+        libc::FUTEX_WAKE,
+        nr,
+        libc::PT_NULL,
+        libc::PT_NULL,
+        0u32,
+    )
+}
+
+/// Sleep as long as `addr` still holds `val`
+///
+/// # Safety
+///
+/// `addr` has to point to a live futex word
+pub unsafe fn futex_wait(addr: *const AtomicU32, val: u32) -> i64 {
+    libc::syscall(
+        libc::SYS_futex,
+        addr,
+        libc::FUTEX_WAIT,
+        val,
+        libc::PT_NULL,
+        libc::PT_NULL,
+        0u32,
+    )
+}
+
+// Not in libc: the bitset that matches every wake
+const FUTEX_BITSET_MATCH_ANY: u32 = 0xffffffff;
+
+/// Sleep as long as `addr` still holds `val`, but not past `deadline`, an absolute CLOCK_MONOTONIC
+/// time. Unlike the relative timeout of FUTEX_WAIT, it doesn't need adjusting when the wait has to
+/// be retried.
+///
+/// # Safety
+///
+/// `addr` has to point to a live futex word
+pub unsafe fn futex_wait_until(addr: *const AtomicU32, val: u32, deadline: &timespec) -> i64 {
+    libc::syscall(
+        libc::SYS_futex,
+        addr,
+        libc::FUTEX_WAIT_BITSET,
+        val,
+        deadline as *const timespec,
+        libc::PT_NULL,
+        FUTEX_BITSET_MATCH_ANY,
+    )
+}
+
+/// `deadline` as a CLOCK_MONOTONIC time, which is the clock Instant uses, though it won't say so
+pub fn monotonic_timespec(deadline: Instant) -> timespec {
+    let mut now = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now);
+    }
+
+    // A deadline that has already passed stays at now
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    let nsec = now.tv_nsec + remaining.subsec_nanos() as libc::c_long;
+    timespec {
+        tv_sec: now.tv_sec + remaining.as_secs() as libc::time_t + nsec / 1_000_000_000,
+        tv_nsec: nsec % 1_000_000_000,
+    }
+}
+
+/// Wake up to `nr_wake` waiters sleeping on `addr` and move up to `nr_requeue` of the rest over to
+/// sleep on `addr2` instead, but only if `addr` still holds `val`
+///
+/// # Safety
+///
+/// `addr` and `addr2` have to point to live futex words
+pub unsafe fn futex_cmp_requeue(
+    addr: *const AtomicU32,
+    nr_wake: c_int,
+    nr_requeue: c_int,
+    addr2: *const AtomicU32,
+    val: u32,
+) -> i64 {
+    // The number to requeue goes where the timeout would be for the other operations
+    libc::syscall(
+        libc::SYS_futex,
+        addr,
+        libc::FUTEX_CMP_REQUEUE,
+        nr_wake,
+        nr_requeue as libc::c_long,
+        addr2,
+        val,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{futex_wait_until, monotonic_timespec};
+    use std::io::Error;
+    use std::sync::atomic::AtomicU32;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn wait_until_times_out() {
+        let word = AtomicU32::new(0);
+        let start = Instant::now();
+        let deadline = monotonic_timespec(start + Duration::from_millis(50));
+
+        let ret = unsafe { futex_wait_until(&word, 0, &deadline) };
+        assert_eq!(ret, -1);
+        assert_eq!(Error::last_os_error().raw_os_error(), Some(libc::ETIMEDOUT));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn wait_until_returns_if_changed() {
+        let word = AtomicU32::new(1);
+        let deadline = monotonic_timespec(Instant::now() + Duration::from_secs(10));
+
+        let ret = unsafe { futex_wait_until(&word, 0, &deadline) };
+        assert_eq!(ret, -1);
+        assert_eq!(Error::last_os_error().raw_os_error(), Some(libc::EAGAIN));
+    }
+}
@@ -0,0 +1,186 @@
+// A minimal init for the first process of a pid namespace, along the lines of tini. Whatever ends up
+// as pid 1 inherits every orphan in the namespace and has to wait for them, or they stay zombies
+// forever. It also gets no default signal handling from the kernel, so a SIGTERM sent to it would
+// just be dropped instead of reaching the program that's actually running.
+
+use libc::{c_char, c_int, pid_t};
+use std::ffi::CString;
+use std::io::{Error, Result};
+use std::mem;
+use std::ptr;
+
+// Signals that get passed on to the target instead of being handled by us
+const FORWARDED: [c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+pub struct Exit {
+    // The target's status as returned by waitpid
+    pub status: c_int,
+
+    // How many other processes we waited for along the way
+    pub reaped: usize,
+}
+
+impl Exit {
+    // The exit code a shell would report for the target
+    pub fn code(&self) -> c_int {
+        if libc::WIFEXITED(self.status) {
+            libc::WEXITSTATUS(self.status)
+        } else if libc::WIFSIGNALED(self.status) {
+            128 + libc::WTERMSIG(self.status)
+        } else {
+            1
+        }
+    }
+}
+
+// Run `args` as a child and look after it until it exits: pass on the forwarded signals and wait for
+// every child we get, whether we started it or it was orphaned and handed to us. Once the target is
+// gone, anything that already exited is reaped too. Anything still running is left alone, which for
+// pid 1 means the kernel kills it as soon as we exit.
+//
+// Besides pid 1 of a namespace, this is also useful for a child subreaper (PR_SET_CHILD_SUBREAPER),
+// which gets orphans the same way. The signals are blocked in the calling thread while this runs, so
+// it should be the only thread.
+pub fn run(args: &[CString]) -> Result<Exit> {
+    if args.is_empty() {
+        return Err(Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let argv: Vec<*const c_char> = args
+        .iter()
+        .map(|arg| arg.as_ptr())
+        .chain([ptr::null()])
+        .collect();
+
+    unsafe {
+        // Blocked signals stay pending until sigwaitinfo picks them up, so none get lost between
+        // forking and waiting. It also means the kernel delivers them to pid 1 at all: it only
+        // drops signals for pid 1 that would get the default action.
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGCHLD);
+        for signal in FORWARDED {
+            libc::sigaddset(&mut set, signal);
+        }
+        let mut old: libc::sigset_t = mem::zeroed();
+        libc::sigprocmask(libc::SIG_BLOCK, &set, &mut old);
+
+        let target = libc::fork();
+        if target == -1 {
+            let err = Error::last_os_error();
+            libc::sigprocmask(libc::SIG_SETMASK, &old, ptr::null_mut());
+            return Err(err);
+        }
+        if target == 0 {
+            // The target shouldn't inherit our blocked signals, it's the one that's meant to get them
+            libc::sigprocmask(libc::SIG_SETMASK, &old, ptr::null_mut());
+            libc::execvp(argv[0], argv.as_ptr());
+            libc::_exit(127);
+        }
+
+        let result = supervise(target, &set);
+        libc::sigprocmask(libc::SIG_SETMASK, &old, ptr::null_mut());
+        result
+    }
+}
+
+unsafe fn supervise(target: pid_t, set: &libc::sigset_t) -> Result<Exit> {
+    let mut reaped = 0;
+    let mut info: libc::siginfo_t = mem::zeroed();
+
+    loop {
+        let signal = libc::sigwaitinfo(set, &mut info);
+        if signal == -1 {
+            let err = Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EINTR) {
+                continue;
+            }
+            return Err(err);
+        }
+
+        if signal != libc::SIGCHLD {
+            // The target may be gone already, but then we'll see its SIGCHLD next
+            libc::kill(target, signal);
+            continue;
+        }
+
+        // Several children exiting at once only leave one SIGCHLD pending, so wait for everything
+        // that's ready
+        let mut target_status = None;
+        loop {
+            let mut status: c_int = 0;
+            let pid = libc::waitpid(-1, &mut status, libc::WNOHANG);
+            if pid <= 0 {
+                break;
+            }
+            if pid == target {
+                target_status = Some(status);
+            } else {
+                reaped += 1;
+            }
+        }
+
+        if let Some(status) = target_status {
+            return Ok(Exit { status, reaped });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run;
+    use std::ffi::CString;
+    use std::io::Error;
+
+    #[test]
+    fn orphans_are_reaped() {
+        // Two subshells each start a sleep in the background and exit right away, so the sleeps are
+        // orphaned. The target outlives them and then exits with a status of its own.
+        let args = [
+            "/bin/sh",
+            "-c",
+            "(sleep 0.1 &); (sleep 0.1 &); sleep 0.5; exit 3",
+        ]
+        .map(|arg| CString::new(arg).unwrap());
+
+        // The test harness has other threads and children of its own, so do this in a process of
+        // our own that's a subreaper, which makes the orphans ours like they would be for pid 1
+        unsafe {
+            let pid = libc::fork();
+            assert_ne!(pid, -1, "fork: {}", Error::last_os_error());
+            if pid == 0 {
+                libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1);
+
+                let code = match run(&args) {
+                    Ok(exit) if exit.code() != 3 => 10,
+                    // The subshells are the target's to wait for, but the sleeps are ours
+                    Ok(exit) if exit.reaped < 2 => 11,
+                    Ok(_) => {
+                        // Nothing left over, not even a zombie
+                        let mut status = 0;
+                        if libc::waitpid(-1, &mut status, libc::WNOHANG) == -1
+                            && Error::last_os_error().raw_os_error() == Some(libc::ECHILD)
+                        {
+                            0
+                        } else {
+                            12
+                        }
+                    }
+                    Err(_) => 13,
+                };
+                libc::_exit(code);
+            }
+
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert!(libc::WIFEXITED(status));
+            match libc::WEXITSTATUS(status) {
+                0 => {}
+                10 => panic!("the target's exit status got lost"),
+                11 => panic!("not all orphans were reaped"),
+                12 => panic!("children were left behind"),
+                code => panic!("run failed ({})", code),
+            }
+        }
+    }
+}
@@ -1,3 +1,17 @@
+pub mod arena;
+pub mod cgroup;
+pub mod clone3;
+pub mod futex;
+pub mod init;
+pub mod netlink;
+pub mod pidfd;
+pub mod robust;
+pub mod seccomp;
+pub mod stack;
+pub mod sync;
+pub mod thread;
+pub mod userns;
+
 #[macro_export]
 macro_rules! debug {
     ($($arg:tt)*) => {{
@@ -0,0 +1,363 @@
+// Just enough rtnetlink to set up networking in a new network namespace: bringing links up, giving
+// them addresses and creating veth pairs. This is what `ip link` and `ip addr` do under the hood.
+
+use libc::{c_int, c_void, nlmsghdr, pid_t};
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::net::Ipv4Addr;
+use std::ptr;
+
+// Messages and attributes are padded to this
+const ALIGN: usize = 4;
+
+// The veth driver's attribute holding the peer's link message
+const VETH_INFO_PEER: u16 = 1;
+
+// Missing from libc
+#[repr(C)]
+#[derive(Default)]
+struct IfInfoMsg {
+    family: u8,
+    _pad: u8,
+    kind: u16,
+    index: i32,
+    flags: u32,
+    change: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct IfAddrMsg {
+    family: u8,
+    prefix_len: u8,
+    flags: u8,
+    scope: u8,
+    index: u32,
+}
+
+fn align(len: usize) -> usize {
+    (len + ALIGN - 1) & !(ALIGN - 1)
+}
+
+// A request being put together: a header, a fixed struct depending on the type, then attributes,
+// which can nest. Lengths are filled in once everything inside is known.
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(kind: u16, flags: c_int) -> Self {
+        let mut message = Message { buf: Vec::new() };
+        message.push(&nlmsghdr {
+            nlmsg_len: 0,
+            nlmsg_type: kind,
+            nlmsg_flags: (libc::NLM_F_REQUEST | libc::NLM_F_ACK | flags) as u16,
+            nlmsg_seq: 0,
+            nlmsg_pid: 0,
+        });
+        message
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    fn push<T>(&mut self, value: &T) {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
+        };
+        self.push_bytes(bytes);
+    }
+
+    // Returns where the attribute starts, to finish it with `end` after pushing nested attributes
+    fn begin(&mut self, kind: u16) -> usize {
+        let start = self.buf.len();
+        self.push(&libc::nlattr {
+            nla_len: 0,
+            nla_type: kind,
+        });
+        start
+    }
+
+    fn end(&mut self, start: usize) {
+        let len = (self.buf.len() - start) as u16;
+        self.buf[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+
+    fn attr(&mut self, kind: u16, data: &[u8]) {
+        let start = self.begin(kind);
+        // The length covers the data but not the padding after it
+        self.buf.extend_from_slice(data);
+        self.end(start);
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    fn attr_str(&mut self, kind: u16, value: &str) {
+        let value = CString::new(value).expect("name contains a nul byte");
+        self.attr(kind, value.as_bytes_with_nul());
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+// A NETLINK_ROUTE socket, talking to the network namespace we were in when it was opened
+pub struct Netlink {
+    fd: c_int,
+    seq: u32,
+}
+
+impl Netlink {
+    pub fn open() -> Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(Netlink { fd, seq: 0 })
+    }
+
+    // Send a request and wait for the kernel to acknowledge it. Every request asks for an ack, which
+    // is an error message with an error of 0 if it worked.
+    fn request(&mut self, message: Message) -> Result<()> {
+        self.seq += 1;
+        let buf = message.finish(self.seq);
+
+        let mut kernel: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        kernel.nl_family = libc::AF_NETLINK as u16;
+        let sent = unsafe {
+            libc::sendto(
+                self.fd,
+                buf.as_ptr() as *const c_void,
+                buf.len(),
+                0,
+                &kernel as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if sent == -1 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut reply = [0u8; 8192];
+        loop {
+            let len =
+                unsafe { libc::recv(self.fd, reply.as_mut_ptr() as *mut c_void, reply.len(), 0) };
+            if len == -1 {
+                return Err(Error::last_os_error());
+            }
+
+            let mut offset = 0;
+            while offset + mem::size_of::<nlmsghdr>() <= len as usize {
+                let header: nlmsghdr =
+                    unsafe { ptr::read_unaligned(reply[offset..].as_ptr() as *const nlmsghdr) };
+                if header.nlmsg_len == 0 {
+                    break;
+                }
+
+                if header.nlmsg_seq == self.seq && header.nlmsg_type == libc::NLMSG_ERROR as u16 {
+                    let error: libc::nlmsgerr = unsafe {
+                        ptr::read_unaligned(reply[offset + mem::size_of::<nlmsghdr>()..].as_ptr()
+                            as *const libc::nlmsgerr)
+                    };
+                    return match error.error {
+                        0 => Ok(()),
+                        errno => Err(Error::from_raw_os_error(-errno)),
+                    };
+                }
+
+                offset += align(header.nlmsg_len as usize);
+            }
+        }
+    }
+
+    // Like `ip link set <link> up`
+    pub fn set_up(&mut self, index: u32) -> Result<()> {
+        self.request(Message::set_up(index))
+    }
+
+    // Like `ip addr add <address>/<prefix_len> dev <link>`
+    pub fn add_address(&mut self, index: u32, address: Ipv4Addr, prefix_len: u8) -> Result<()> {
+        self.request(Message::add_address(index, address, prefix_len))
+    }
+
+    // Like `ip link add <name> type veth peer name <peer> netns <peer_pid>`. A veth pair is two links
+    // connected like a cable, and the peer gets created straight in the network namespace of
+    // `peer_pid`. Both ends go away when either namespace does.
+    pub fn add_veth(&mut self, name: &str, peer: &str, peer_pid: pid_t) -> Result<()> {
+        self.request(Message::add_veth(name, peer, peer_pid))
+    }
+}
+
+// The requests themselves, kept apart from sending them so they can be checked byte by byte
+impl Message {
+    fn set_up(index: u32) -> Self {
+        let mut message = Message::new(libc::RTM_NEWLINK, 0);
+        message.push(&IfInfoMsg {
+            family: libc::AF_UNSPEC as u8,
+            index: index as i32,
+            flags: libc::IFF_UP as u32,
+            change: libc::IFF_UP as u32,
+            ..Default::default()
+        });
+        message
+    }
+
+    fn add_address(index: u32, address: Ipv4Addr, prefix_len: u8) -> Self {
+        let mut message = Message::new(libc::RTM_NEWADDR, libc::NLM_F_CREATE | libc::NLM_F_EXCL);
+        message.push(&IfAddrMsg {
+            family: libc::AF_INET as u8,
+            prefix_len,
+            index,
+            ..Default::default()
+        });
+        // For point-to-point links these differ, for everything else they're the same
+        message.attr(libc::IFA_LOCAL, &address.octets());
+        message.attr(libc::IFA_ADDRESS, &address.octets());
+        message
+    }
+
+    fn add_veth(name: &str, peer: &str, peer_pid: pid_t) -> Self {
+        let mut message = Message::new(libc::RTM_NEWLINK, libc::NLM_F_CREATE | libc::NLM_F_EXCL);
+        message.push(&IfInfoMsg::default());
+        message.attr_str(libc::IFLA_IFNAME, name);
+
+        let link_info = message.begin(libc::IFLA_LINKINFO);
+        message.attr_str(libc::IFLA_INFO_KIND, "veth");
+        let info_data = message.begin(libc::IFLA_INFO_DATA);
+
+        // The peer is described by a link message of its own
+        let peer_info = message.begin(VETH_INFO_PEER);
+        message.push(&IfInfoMsg::default());
+        message.attr_str(libc::IFLA_IFNAME, peer);
+        message.attr(libc::IFLA_NET_NS_PID, &(peer_pid as u32).to_ne_bytes());
+        message.end(peer_info);
+
+        message.end(info_data);
+        message.end(link_info);
+
+        message
+    }
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+pub fn link_index(name: &str) -> Result<u32> {
+    let name = CString::new(name).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Message;
+    use std::net::Ipv4Addr;
+
+    // The header every request starts with: length, type, flags, sequence number and port id
+    fn header(len: u32, kind: u16, flags: i32, seq: u32) -> Vec<u8> {
+        let flags = (libc::NLM_F_REQUEST | libc::NLM_F_ACK | flags) as u16;
+        [
+            &len.to_ne_bytes()[..],
+            &kind.to_ne_bytes(),
+            &flags.to_ne_bytes(),
+            &seq.to_ne_bytes(),
+            &0u32.to_ne_bytes(),
+        ]
+        .concat()
+    }
+
+    // An attribute header, with the length covering the header and the data but not the padding
+    fn nla(len: u16, kind: u16) -> Vec<u8> {
+        [len.to_ne_bytes(), kind.to_ne_bytes()].concat()
+    }
+
+    // struct ifinfomsg with only the index, flags and change set
+    fn ifinfo(index: i32, flags: u32, change: u32) -> Vec<u8> {
+        [
+            &[0u8, 0, 0, 0][..],
+            &index.to_ne_bytes(),
+            &flags.to_ne_bytes(),
+            &change.to_ne_bytes(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn set_up_is_a_header_and_a_link_message() {
+        let up = libc::IFF_UP as u32;
+        let expected = [header(32, libc::RTM_NEWLINK, 0, 7), ifinfo(3, up, up)].concat();
+        assert_eq!(Message::set_up(3).finish(7), expected);
+    }
+
+    #[test]
+    fn addresses_are_four_byte_attributes() {
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        let expected = [
+            header(40, libc::RTM_NEWADDR, flags, 1),
+            vec![libc::AF_INET as u8, 24, 0, 0],
+            2u32.to_ne_bytes().to_vec(),
+            nla(8, libc::IFA_LOCAL),
+            vec![10, 0, 0, 1],
+            nla(8, libc::IFA_ADDRESS),
+            vec![10, 0, 0, 1],
+        ]
+        .concat();
+        assert_eq!(
+            Message::add_address(2, Ipv4Addr::new(10, 0, 0, 1), 24).finish(1),
+            expected
+        );
+    }
+
+    // Names that aren't a multiple of four long get padded, and the nested attributes' lengths cover
+    // everything inside them, padding included
+    #[test]
+    fn veth_nests_the_peer_in_the_link_info() {
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        let peer = [
+            nla(40, super::VETH_INFO_PEER),
+            ifinfo(0, 0, 0),
+            nla(10, libc::IFLA_IFNAME),
+            b"peer0\0\0\0".to_vec(),
+            nla(8, libc::IFLA_NET_NS_PID),
+            1234u32.to_ne_bytes().to_vec(),
+        ]
+        .concat();
+        let link_info = [
+            nla(60, libc::IFLA_LINKINFO),
+            nla(9, libc::IFLA_INFO_KIND),
+            b"veth\0\0\0\0".to_vec(),
+            nla(44, libc::IFLA_INFO_DATA),
+            peer,
+        ]
+        .concat();
+        let expected = [
+            header(104, libc::RTM_NEWLINK, flags, 2),
+            ifinfo(0, 0, 0),
+            nla(11, libc::IFLA_IFNAME),
+            b"veth0a\0\0".to_vec(),
+            link_info,
+        ]
+        .concat();
+
+        let bytes = Message::add_veth("veth0a", "peer0", 1234).finish(2);
+        assert_eq!(bytes.len(), 104);
+        assert_eq!(bytes, expected);
+    }
+}
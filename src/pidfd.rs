@@ -0,0 +1,88 @@
+use libc::{c_int, pid_t, siginfo_t};
+use std::io::{Error, Result};
+use std::mem;
+use std::ptr;
+
+// A file descriptor referring to a process. Unlike a pid it can't be recycled, so signals and waits
+// always go to the right process.
+pub struct PidFd(c_int);
+
+impl PidFd {
+    pub fn open(pid: pid_t) -> Result<Self> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(PidFd(fd as c_int))
+    }
+
+    /// # Safety
+    ///
+    /// `fd` has to be a pidfd nobody else will close
+    pub unsafe fn from_raw(fd: c_int) -> Self {
+        PidFd(fd)
+    }
+
+    pub fn as_raw(&self) -> c_int {
+        self.0
+    }
+
+    // A pidfd becomes readable once the process has exited. Returns whether it has, waiting for up to
+    // `timeout_ms` (or forever if negative).
+    pub fn poll(&self, timeout_ms: c_int) -> Result<bool> {
+        let mut pfd = libc::pollfd {
+            fd: self.0,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            match unsafe { libc::poll(&mut pfd, 1, timeout_ms) } {
+                -1 if Error::last_os_error().raw_os_error() == Some(libc::EINTR) => continue,
+                -1 => return Err(Error::last_os_error()),
+                n => return Ok(n > 0),
+            }
+        }
+    }
+
+    pub fn send_signal(&self, signal: c_int) -> Result<()> {
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                self.0,
+                signal,
+                ptr::null::<siginfo_t>(),
+                0,
+            )
+        };
+        if ret == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Reap the process. `options` are passed on to waitid along with WEXITED, e.g. __WCLONE for
+    // children that don't send SIGCHLD.
+    pub fn wait(&self, options: c_int) -> Result<siginfo_t> {
+        unsafe {
+            let mut info: siginfo_t = mem::zeroed();
+            if libc::waitid(
+                libc::P_PIDFD,
+                self.0 as libc::id_t,
+                &mut info,
+                libc::WEXITED | options,
+            ) == -1
+            {
+                return Err(Error::last_os_error());
+            }
+            Ok(info)
+        }
+    }
+}
+
+impl Drop for PidFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
@@ -0,0 +1,453 @@
+// A mutex that still gets handed on when whoever holds it dies, which for one shared between processes
+// is only a kill -9 away. Our Mutex would stay locked for good, with everyone else asleep on it.
+//
+// The kernel does most of the work. The futex word holds the id of the thread that has it locked, and
+// every thread gives the kernel a list of the robust mutexes it's holding with set_robust_list. When a
+// thread exits, however it exits, the kernel goes through its list, marks every word that still has
+// its id in it with FUTEX_OWNER_DIED and wakes a waiter. The next one to lock it gets OwnerDied
+// instead of a plain guard, so it knows the data might be halfway through a change.
+//
+// There's only one robust list per thread, and glibc registers one of its own for robust pthread
+// mutexes. Ours replaces it, so those don't get cleaned up anymore in a thread that uses this. That's
+// for the program to decide, so nothing gets locked until it has called enable.
+
+use crate::arena::ProcessShared;
+use crate::futex::{futex_wait, futex_wake};
+use libc::{c_long, pid_t};
+use std::cell::{Cell, UnsafeCell};
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{self, offset_of};
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Once;
+
+// Not in libc yet
+const FUTEX_WAITERS: u32 = 0x8000_0000;
+const FUTEX_OWNER_DIED: u32 = 0x4000_0000;
+const FUTEX_TID_MASK: u32 = 0x3fff_ffff;
+
+// The kernel's struct robust_list, the link in a list of held mutexes
+#[repr(C)]
+struct RobustList {
+    next: *mut RobustList,
+}
+
+// The kernel's struct robust_list_head
+#[repr(C)]
+struct RobustListHead {
+    list: RobustList,
+
+    // Where the futex word is, relative to each link
+    futex_offset: c_long,
+
+    // The mutex we're about to lock or unlock, for when we die halfway through it and it isn't on the
+    // list (yet, or anymore) while its word says it's ours
+    list_op_pending: *mut RobustList,
+}
+
+// The link goes first and the word right after it, so the offset between them is the same for every T
+#[repr(C)]
+pub struct RobustMutex<T> {
+    link: UnsafeCell<RobustList>,
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RobustMutex<T> {}
+unsafe impl<T: Send> Sync for RobustMutex<T> {}
+
+// The link points at other links in the owner's memory, but only the owner ever follows it
+unsafe impl<T: ProcessShared> ProcessShared for RobustMutex<T> {}
+
+pub struct RobustMutexGuard<'a, T> {
+    mutex: &'a RobustMutex<T>,
+
+    // Unlocking has to take the mutex off the list of the thread that locked it, so unlike with our
+    // Mutex, the guard can't go to another thread
+    _data: PhantomData<(&'a mut T, *const ())>,
+}
+
+// The mutex is locked, but whoever had it before died holding it. Whatever it was protecting may need
+// fixing up before it can be used again.
+pub struct OwnerDied<'a, T> {
+    guard: RobustMutexGuard<'a, T>,
+}
+
+pub type LockResult<'a, T> = Result<RobustMutexGuard<'a, T>, OwnerDied<'a, T>>;
+
+impl<'a, T> OwnerDied<'a, T> {
+    pub fn into_inner(self) -> RobustMutexGuard<'a, T> {
+        self.guard
+    }
+}
+
+impl<T> fmt::Debug for OwnerDied<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OwnerDied { .. }")
+    }
+}
+
+// What each thread keeps for itself. The list head has to stay where it is for as long as the thread
+// is around, which a thread-local does.
+struct Registration {
+    head: UnsafeCell<RobustListHead>,
+
+    // Our thread id once the head is registered, 0 before
+    tid: Cell<pid_t>,
+}
+
+thread_local! {
+    static THREAD: Registration = const {
+        Registration {
+            head: UnsafeCell::new(RobustListHead {
+                list: RobustList {
+                    next: ptr::null_mut(),
+                },
+                futex_offset: (offset_of!(RobustMutex<()>, state)
+                    - offset_of!(RobustMutex<()>, link)) as c_long,
+                list_op_pending: ptr::null_mut(),
+            }),
+            tid: Cell::new(0),
+        }
+    };
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Let every thread that locks a RobustMutex replace glibc's robust list with ours, which turns off
+// owner-died recovery for robust pthread mutexes in those threads, whoever they belong to. Only call
+// this in a program that doesn't use any, directly or through a library.
+pub fn enable() {
+    static AT_FORK: Once = Once::new();
+    AT_FORK.call_once(|| unsafe {
+        libc::pthread_atfork(None, None, Some(forget_in_child));
+    });
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+// A child started with clone or clone3 starts out without a robust list, and with a copy of the
+// parent's thread id and of the list of mutexes the parent holds, none of which are the child's. Only
+// glibc's fork runs the atfork handler, everything else that starts one in this crate calls this.
+pub(crate) fn forget_registration() {
+    THREAD.with(|thread| thread.tid.set(0));
+}
+
+extern "C" fn forget_in_child() {
+    forget_registration();
+}
+
+// Run `f` with our list head, registering it first if this thread hasn't yet
+fn with_head<R>(f: impl FnOnce(&mut RobustListHead, u32) -> R) -> R {
+    assert!(
+        ENABLED.load(Ordering::Relaxed),
+        "RobustMutex locked before robust::enable()"
+    );
+
+    THREAD.with(|thread| {
+        let head = unsafe { &mut *thread.head.get() };
+        if thread.tid.get() == 0 {
+            // An empty list points back at the head
+            head.list.next = &mut head.list;
+            head.list_op_pending = ptr::null_mut();
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_set_robust_list,
+                    head as *mut RobustListHead,
+                    mem::size_of::<RobustListHead>(),
+                )
+            };
+            if ret == -1 {
+                panic!(
+                    "set_robust_list failed: {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+            thread.tid.set(unsafe { libc::gettid() });
+        }
+        f(head, thread.tid.get() as u32)
+    })
+}
+
+impl<T> RobustMutex<T> {
+    pub const fn new(value: T) -> Self {
+        RobustMutex {
+            link: UnsafeCell::new(RobustList {
+                next: ptr::null_mut(),
+            }),
+            state: AtomicU32::new(0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> LockResult<'_, T> {
+        let link = self.link.get();
+        let owner_died = with_head(|head, tid| {
+            head.list_op_pending = link;
+
+            // Once we've slept we can't know whether anyone else still is, so we keep the waiters bit
+            // set when we get it, like the contended state of our Mutex
+            let mut waiters = 0;
+            let mut state = self.state.load(Ordering::Relaxed);
+            let owner_died = loop {
+                if state & FUTEX_TID_MASK == 0 {
+                    // Unlocked, or the kernel took it off a thread that died
+                    let locked = tid | waiters | (state & FUTEX_WAITERS);
+                    match self.state.compare_exchange(
+                        state,
+                        locked,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => break state & FUTEX_OWNER_DIED != 0,
+                        Err(actual) => state = actual,
+                    }
+                    continue;
+                }
+
+                if state & FUTEX_WAITERS == 0 {
+                    if let Err(actual) = self.state.compare_exchange(
+                        state,
+                        state | FUTEX_WAITERS,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ) {
+                        state = actual;
+                        continue;
+                    }
+                }
+                unsafe {
+                    futex_wait(&self.state, state | FUTEX_WAITERS);
+                }
+                waiters = FUTEX_WAITERS;
+                state = self.state.load(Ordering::Relaxed);
+            };
+
+            // It's ours now, so it goes on the list before it stops being pending
+            unsafe {
+                (*link).next = head.list.next;
+            }
+            head.list.next = link;
+            head.list_op_pending = ptr::null_mut();
+            owner_died
+        });
+
+        let guard = RobustMutexGuard {
+            mutex: self,
+            _data: PhantomData,
+        };
+        if owner_died {
+            Err(OwnerDied { guard })
+        } else {
+            Ok(guard)
+        }
+    }
+
+    fn unlock(&self) {
+        let link = self.link.get();
+        with_head(|head, _| {
+            head.list_op_pending = link;
+
+            // The list is only singly linked, but it only holds the mutexes we have locked right now.
+            // Getting back to the head means the mutex isn't on it, which happens when the guard
+            // crossed a fork or clone into a child with a list of its own. Going round again would
+            // never end, and leaving it locked is all we can do.
+            let end: *mut RobustList = &mut head.list;
+            let mut prev = end;
+            unsafe {
+                while (*prev).next != link {
+                    prev = (*prev).next;
+                    if prev == end {
+                        head.list_op_pending = ptr::null_mut();
+                        panic!("RobustMutexGuard dropped by a thread that didn't lock it");
+                    }
+                }
+                (*prev).next = (*link).next;
+            }
+
+            if self.state.swap(0, Ordering::Release) & FUTEX_WAITERS != 0 {
+                unsafe {
+                    futex_wake(&self.state, 1);
+                }
+            }
+            head.list_op_pending = ptr::null_mut();
+        });
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T> Deref for RobustMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for RobustMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for RobustMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{enable, RobustMutex};
+    use crate::arena::SharedArena;
+    use crate::clone3::{clone3, CloneArgs};
+    use crate::sync::Sem;
+    use crate::thread::spawn;
+    use std::mem;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+
+    // Not just processes, a thread that exits holding it gets it taken off it too
+    #[test]
+    fn exited_thread_hands_the_lock_on() {
+        enable();
+        let counter = Arc::new(RobustMutex::new(0u64));
+        let holder = counter.clone();
+        spawn(move || {
+            let mut guard = holder.lock().unwrap();
+            *guard += 1;
+            mem::forget(guard);
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+
+        let mut guard = match counter.lock() {
+            Ok(_) => panic!("the lock came back without OwnerDied"),
+            Err(died) => died.into_inner(),
+        };
+        assert_eq!(*guard, 1);
+        *guard += 1;
+        drop(guard);
+        assert_eq!(*counter.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn nested_locks_come_off_the_list_in_any_order() {
+        enable();
+        let (a, b, c) = (
+            RobustMutex::new(1),
+            RobustMutex::new(2),
+            RobustMutex::new(3),
+        );
+        let guard_a = a.lock().unwrap();
+        let guard_b = b.lock().unwrap();
+        let guard_c = c.lock().unwrap();
+        drop(guard_b);
+        drop(guard_a);
+        drop(guard_c);
+        assert_eq!(
+            *a.lock().unwrap() + *b.lock().unwrap() + *c.lock().unwrap(),
+            6
+        );
+    }
+
+    // The child gets killed in the middle of changing a pair that's meant to stay equal, with us asleep
+    // waiting for the lock
+    #[test]
+    fn killed_owner_hands_the_lock_on() {
+        enable();
+        let arena = SharedArena::new(4096).unwrap();
+        let pair = arena.alloc(RobustMutex::new([0u32; 2]));
+        let locked = arena.alloc(Sem::new(0));
+
+        let pid = unsafe { libc::fork() };
+        assert_ne!(pid, -1);
+        if pid == 0 {
+            let mut guard = pair.lock().unwrap();
+            guard[0] += 1;
+            locked.up();
+            loop {
+                unsafe { libc::pause() };
+            }
+        }
+
+        locked.down();
+        let killer = spawn(move || unsafe {
+            libc::usleep(50_000);
+            libc::kill(pid, libc::SIGKILL);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        })
+        .unwrap();
+
+        let mut guard = match pair.lock() {
+            Ok(_) => panic!("the lock came back without OwnerDied"),
+            Err(died) => died.into_inner(),
+        };
+        killer.join().unwrap();
+        assert_eq!(*guard, [1, 0]);
+        guard[1] = guard[0];
+        drop(guard);
+
+        // Fixed up, and the next lock is an ordinary one
+        assert_eq!(*pair.lock().unwrap(), [1, 1]);
+    }
+
+    // The child's list is a new, empty one, so the guard it got a copy of can't be on it. Dropping
+    // that used to go round the list forever.
+    #[test]
+    fn guard_dropped_after_fork_panics() {
+        enable();
+        let mutex = RobustMutex::new(0u32);
+        let guard = mutex.lock().unwrap();
+
+        let pid = unsafe { libc::fork() };
+        assert_ne!(pid, -1);
+        if pid == 0 {
+            let dropped = panic::catch_unwind(AssertUnwindSafe(|| drop(guard)));
+            unsafe { libc::_exit(if dropped.is_err() { 0 } else { 1 }) };
+        }
+
+        let mut status = 0;
+        unsafe {
+            libc::waitpid(pid, &mut status, 0);
+        }
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0, "the child dropped the guard");
+        drop(guard);
+        assert_eq!(*mutex.lock().unwrap(), 0);
+    }
+
+    // Only glibc's fork resets what the parent registered, a child from clone3 has to be told
+    #[test]
+    fn clone3_child_registers_its_own_list() {
+        enable();
+        drop(RobustMutex::new(()).lock());
+
+        let arena = SharedArena::new(4096).unwrap();
+        let mutex = arena.alloc(RobustMutex::new(0u32));
+
+        let args = CloneArgs {
+            exit_signal: libc::SIGCHLD as u64,
+            ..Default::default()
+        };
+        let pid = unsafe { clone3(&args) }.unwrap();
+        if pid == 0 {
+            // Exiting with the lock held, which only gets noticed if it's on a list the kernel knows
+            mem::forget(mutex.lock());
+            unsafe { libc::_exit(0) };
+        }
+        unsafe {
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+
+        assert!(
+            mutex.lock().is_err(),
+            "the lock came back without OwnerDied"
+        );
+    }
+}
@@ -0,0 +1,306 @@
+// seccomp filters: small classic BPF programs the kernel runs on every syscall, looking at the
+// syscall number and architecture and deciding what happens. Here they're limited to a list of
+// syscalls with an action each, and a default for everything else.
+
+use libc::{c_long, c_uint, sock_filter};
+use std::io::{Error, Result};
+
+// The architecture the kernel reports for our syscalls. A filter that didn't check it could be
+// bypassed by making the same call with another architecture's numbering, like i386 int 0x80 on
+// x86_64.
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc00000b7;
+
+// x32 syscalls share the x86_64 architecture value and are told apart by this bit in the number
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x40000000;
+
+// Offsets into struct seccomp_data, which is what the program gets to look at
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+
+    // Fail the syscall with this errno without running it
+    Errno(u16),
+
+    // Send SIGSYS, with the syscall number and architecture in the siginfo
+    Trap,
+
+    // Allow, but log it to the audit log
+    Log,
+
+    // Kill the whole process as if by an uncatchable SIGSYS
+    Kill,
+}
+
+impl Action {
+    fn ret(self) -> u32 {
+        match self {
+            Action::Allow => libc::SECCOMP_RET_ALLOW,
+            Action::Errno(errno) => {
+                libc::SECCOMP_RET_ERRNO | (errno as u32 & libc::SECCOMP_RET_DATA)
+            }
+            Action::Trap => libc::SECCOMP_RET_TRAP,
+            Action::Log => libc::SECCOMP_RET_LOG,
+            Action::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+        }
+    }
+}
+
+// Just the instructions the filters need. Jump offsets count instructions to skip.
+struct Assembler {
+    program: Vec<sock_filter>,
+}
+
+impl Assembler {
+    fn load(&mut self, offset: u32) {
+        self.stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, offset);
+    }
+
+    fn jeq(&mut self, k: u32, jt: u8, jf: u8) {
+        self.jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, k, jt, jf);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn jge(&mut self, k: u32, jt: u8, jf: u8) {
+        self.jump(libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K, k, jt, jf);
+    }
+
+    fn ret(&mut self, action: Action) {
+        self.stmt(libc::BPF_RET | libc::BPF_K, action.ret());
+    }
+
+    fn stmt(&mut self, code: u32, k: u32) {
+        self.jump(code, k, 0, 0);
+    }
+
+    fn jump(&mut self, code: u32, k: u32, jt: u8, jf: u8) {
+        self.program.push(sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        });
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Filter {
+    default: Action,
+    rules: Vec<(c_long, Action)>,
+}
+
+impl Filter {
+    pub fn new(default: Action) -> Self {
+        Filter {
+            default,
+            rules: Vec::new(),
+        }
+    }
+
+    // The first rule for a syscall wins
+    pub fn rule(mut self, nr: c_long, action: Action) -> Self {
+        self.rules.push((nr, action));
+        self
+    }
+
+    pub fn rules(&self) -> &[(c_long, Action)] {
+        &self.rules
+    }
+
+    pub fn assemble(&self) -> Program {
+        let mut asm = Assembler {
+            program: Vec::new(),
+        };
+
+        asm.load(ARCH_OFFSET);
+        asm.jeq(AUDIT_ARCH, 1, 0);
+        asm.ret(Action::Kill);
+
+        asm.load(NR_OFFSET);
+        #[cfg(target_arch = "x86_64")]
+        {
+            asm.jge(X32_SYSCALL_BIT, 0, 1);
+            asm.ret(Action::Kill);
+        }
+
+        for &(nr, action) in &self.rules {
+            asm.jeq(nr as u32, 0, 1);
+            asm.ret(action);
+        }
+        asm.ret(self.default);
+
+        Program(asm.program)
+    }
+}
+
+// An assembled filter, ready to be installed without allocating, which matters in children that
+// share our memory
+pub struct Program(Vec<sock_filter>);
+
+impl Program {
+    // Install the filter for the calling thread, and everything it starts from now on. Filters stack
+    // and can't be removed. Unless we have CAP_SYS_ADMIN, no_new_privs has to be set first.
+    pub fn install(&self) -> Result<()> {
+        let prog = libc::sock_fprog {
+            len: self.0.len() as u16,
+            filter: self.0.as_ptr() as *mut sock_filter,
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                0 as c_uint,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        if ret == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+// Make sure nothing we exec can gain privileges, through setuid binaries or file capabilities.
+// Otherwise a filter could be used to trip up a privileged program into doing something it shouldn't.
+pub fn set_no_new_privs() -> Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+// The syscalls we know the names of
+const SYSCALLS: &[(&str, c_long)] = &[
+    ("read", libc::SYS_read as c_long),
+    ("write", libc::SYS_write as c_long),
+    ("openat", libc::SYS_openat as c_long),
+    ("close", libc::SYS_close as c_long),
+    ("mmap", libc::SYS_mmap as c_long),
+    ("mprotect", libc::SYS_mprotect as c_long),
+    ("munmap", libc::SYS_munmap as c_long),
+    ("ioctl", libc::SYS_ioctl as c_long),
+    ("getpid", libc::SYS_getpid as c_long),
+    ("getppid", libc::SYS_getppid as c_long),
+    ("gettid", libc::SYS_gettid as c_long),
+    ("getuid", libc::SYS_getuid as c_long),
+    ("getgid", libc::SYS_getgid as c_long),
+    ("setuid", libc::SYS_setuid as c_long),
+    ("setgid", libc::SYS_setgid as c_long),
+    ("uname", libc::SYS_uname as c_long),
+    ("sethostname", libc::SYS_sethostname as c_long),
+    ("socket", libc::SYS_socket as c_long),
+    ("connect", libc::SYS_connect as c_long),
+    ("bind", libc::SYS_bind as c_long),
+    ("listen", libc::SYS_listen as c_long),
+    ("accept4", libc::SYS_accept4 as c_long),
+    ("clone", libc::SYS_clone as c_long),
+    ("clone3", libc::SYS_clone3 as c_long),
+    ("execve", libc::SYS_execve as c_long),
+    ("exit", libc::SYS_exit as c_long),
+    ("exit_group", libc::SYS_exit_group as c_long),
+    ("rt_sigreturn", libc::SYS_rt_sigreturn as c_long),
+    ("kill", libc::SYS_kill as c_long),
+    ("ptrace", libc::SYS_ptrace as c_long),
+    ("mount", libc::SYS_mount as c_long),
+    ("umount2", libc::SYS_umount2 as c_long),
+    ("unshare", libc::SYS_unshare as c_long),
+    ("setns", libc::SYS_setns as c_long),
+    ("chroot", libc::SYS_chroot as c_long),
+    ("reboot", libc::SYS_reboot as c_long),
+    ("mkdirat", libc::SYS_mkdirat as c_long),
+    ("unlinkat", libc::SYS_unlinkat as c_long),
+    ("renameat2", libc::SYS_renameat2 as c_long),
+    ("fchmodat", libc::SYS_fchmodat as c_long),
+    ("fchownat", libc::SYS_fchownat as c_long),
+    ("nanosleep", libc::SYS_nanosleep as c_long),
+    ("clock_nanosleep", libc::SYS_clock_nanosleep as c_long),
+    ("personality", libc::SYS_personality as c_long),
+];
+
+// The old versions of calls that newer architectures only have the *at variants of
+#[cfg(target_arch = "x86_64")]
+const LEGACY_SYSCALLS: &[(&str, c_long)] = &[
+    ("open", libc::SYS_open as c_long),
+    ("mkdir", libc::SYS_mkdir as c_long),
+    ("rmdir", libc::SYS_rmdir as c_long),
+    ("unlink", libc::SYS_unlink as c_long),
+    ("rename", libc::SYS_rename as c_long),
+    ("chmod", libc::SYS_chmod as c_long),
+    ("chown", libc::SYS_chown as c_long),
+    ("access", libc::SYS_access as c_long),
+    ("fork", libc::SYS_fork as c_long),
+    ("vfork", libc::SYS_vfork as c_long),
+    ("time", libc::SYS_time as c_long),
+];
+#[cfg(not(target_arch = "x86_64"))]
+const LEGACY_SYSCALLS: &[(&str, c_long)] = &[];
+
+// Look up a syscall by name, or take a number as is
+pub fn syscall_number(name: &str) -> Option<c_long> {
+    if let Ok(nr) = name.parse() {
+        return Some(nr);
+    }
+    SYSCALLS
+        .iter()
+        .chain(LEGACY_SYSCALLS)
+        .find(|(known, _)| *known == name)
+        .map(|&(_, nr)| nr)
+}
+
+pub fn syscall_name(nr: c_long) -> Option<&'static str> {
+    SYSCALLS
+        .iter()
+        .chain(LEGACY_SYSCALLS)
+        .find(|&&(_, known)| known == nr)
+        .map(|&(name, _)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{set_no_new_privs, Action, Filter};
+    use std::io::Error;
+
+    #[test]
+    fn denied_syscalls_fail() {
+        let program = Filter::new(Action::Allow)
+            .rule(libc::SYS_getppid, Action::Errno(libc::EACCES as u16))
+            .assemble();
+
+        // Filters can't be removed, so install it in a child
+        unsafe {
+            let pid = libc::fork();
+            assert_ne!(pid, -1, "fork: {}", Error::last_os_error());
+            if pid == 0 {
+                if set_no_new_privs().is_err() || program.install().is_err() {
+                    libc::_exit(10);
+                }
+                if libc::syscall(libc::SYS_getppid) != -1
+                    || Error::last_os_error().raw_os_error() != Some(libc::EACCES)
+                {
+                    libc::_exit(11);
+                }
+                if libc::syscall(libc::SYS_getpid) == -1 {
+                    libc::_exit(12);
+                }
+                libc::_exit(0);
+            }
+
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert!(libc::WIFEXITED(status));
+            match libc::WEXITSTATUS(status) {
+                0 => {}
+                10 => panic!("could not install the filter"),
+                11 => panic!("the denied syscall went through"),
+                12 => panic!("a syscall that wasn't denied failed"),
+                code => panic!("unexpected exit status {}", code),
+            }
+        }
+    }
+}
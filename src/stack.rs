@@ -0,0 +1,87 @@
+use libc::c_void;
+use std::io::{Error, ErrorKind, Result};
+use std::ptr;
+
+// A stack for a cloned child, with a guard page below it so that an overflow faults instead of
+// silently scribbling over whatever happens to be mapped there
+pub struct Stack {
+    base: *mut c_void,
+    len: usize,
+}
+
+// The stack is just memory, whoever ends up running on it is the caller's problem
+unsafe impl Send for Stack {}
+
+// Anything smaller than what glibc will give a thread can't be trusted to get through a call into
+// libc, and zero would leave nothing but the guard page
+pub const MIN_SIZE: usize = libc::PTHREAD_STACK_MIN;
+
+impl Stack {
+    // Map a stack with room for at least `size` bytes
+    pub fn new(size: usize) -> Result<Self> {
+        if size < MIN_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "stack size {} is below the minimum of {} bytes",
+                    size, MIN_SIZE
+                ),
+            ));
+        }
+
+        let page_size = page_size()?;
+
+        // Round up to whole pages, plus one for the guard
+        let len = size.div_ceil(page_size) * page_size + page_size;
+
+        unsafe {
+            let base = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK,
+                -1,
+                0,
+            );
+            if base == libc::MAP_FAILED {
+                return Err(Error::last_os_error());
+            }
+
+            // Stacks grow down, so the guard goes at the lowest address
+            if libc::mprotect(base, page_size, libc::PROT_NONE) == -1 {
+                let err = Error::last_os_error();
+                libc::munmap(base, len);
+                return Err(err);
+            }
+
+            Ok(Stack { base, len })
+        }
+    }
+
+    // The initial stack pointer to give to clone. The ABI wants it 16-byte aligned.
+    pub fn top(&self) -> *mut c_void {
+        let end = self.base as usize + self.len;
+        (end & !15) as *mut c_void
+    }
+
+    // Usable size, not counting the guard page
+    pub fn size(&self) -> usize {
+        self.len - page_size().expect("could not get page size")
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base, self.len);
+        }
+    }
+}
+
+fn page_size() -> Result<usize> {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGE_SIZE) };
+    if size == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(size as usize)
+}
@@ -0,0 +1,557 @@
+// Locks built directly on futexes. The futex calls aren't the private kind, so everything in here works
+// between processes too, when it's placed in memory they share with MAP_SHARED.
+
+use crate::arena::ProcessShared;
+use crate::futex::{
+    futex_cmp_requeue, futex_wait, futex_wait_until, futex_wake, monotonic_timespec,
+};
+use std::cell::UnsafeCell;
+use std::io::Error;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+
+// Locked, and somebody may be sleeping on the futex, so unlocking has to wake them
+const CONTENDED: u32 = 2;
+
+// The mutex from Ulrich Drepper's "Futexes Are Tricky". Locking and unlocking without contention are
+// a single atomic operation each, and only an unlock that might have a sleeper to wake makes a syscall.
+pub struct Mutex<T> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: ProcessShared> ProcessShared for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+
+    // The guard hands out the data like a &mut would, so it should only be Sync when that is. Unlike
+    // with pthreads, there's nothing wrong with unlocking from another thread.
+    _data: PhantomData<&'a mut T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            state: AtomicU32::new(UNLOCKED),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.lock_contended();
+        }
+        MutexGuard {
+            mutex: self,
+            _data: PhantomData,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard {
+                mutex: self,
+                _data: PhantomData,
+            })
+    }
+
+    fn lock_contended(&self) {
+        // Once we've had to wait, we can't know whether anyone else is, so we take the lock as
+        // contended. That costs a needless wake at worst, where taking it as locked could leave
+        // another waiter asleep for good.
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            // Returns straight away if the state changed before we got to sleep
+            unsafe {
+                futex_wait(&self.state, CONTENDED);
+            }
+        }
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            unsafe {
+                futex_wake(&self.state, 1);
+            }
+        }
+    }
+
+    // Having the mutex mutably means nobody else can have it locked
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+// A condition variable for our Mutex. Waiters sleep on a sequence number that every notify bumps, so a
+// notify that comes between unlocking the mutex and going to sleep makes the futex wait return
+// straight away instead of getting lost.
+//
+// Waits can return without a notify, so the condition has to be checked again, which wait_while does.
+pub struct Condvar {
+    seq: AtomicU32,
+
+    // The state word of the mutex the waiters use, for notify_all to requeue them onto. It's an
+    // address in the waiters' memory, so for a condvar shared between processes it only works when
+    // they all have the memory at the same address, like after fork.
+    mutex: AtomicPtr<AtomicU32>,
+}
+
+// The pointer is into the shared memory the mutex is in, see above
+unsafe impl ProcessShared for Condvar {}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            seq: AtomicU32::new(0),
+            mutex: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    // Unlock the mutex, sleep until notified, and lock it again
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        self.mutex.store(
+            &mutex.state as *const AtomicU32 as *mut _,
+            Ordering::Relaxed,
+        );
+
+        // Read before unlocking, so any notify after the unlock changes it
+        let seq = self.seq.load(Ordering::Relaxed);
+        drop(guard);
+
+        unsafe {
+            futex_wait(&self.seq, seq);
+        }
+
+        // We might have been requeued onto the mutex along with others, who only get woken if it's
+        // unlocked as contended
+        mutex.lock_contended();
+        MutexGuard {
+            mutex,
+            _data: PhantomData,
+        }
+    }
+
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Release);
+        unsafe {
+            futex_wake(&self.seq, 1);
+        }
+    }
+
+    // Waking everyone would only have them all fight over the mutex, with all but one going straight
+    // back to sleep on it. So only one is woken, and the rest are moved over to the mutex, where each
+    // unlock wakes the next.
+    pub fn notify_all(&self) {
+        let seq = self.seq.fetch_add(1, Ordering::Release).wrapping_add(1);
+        let mutex = self.mutex.load(Ordering::Relaxed);
+        if mutex.is_null() {
+            // Nobody has ever waited
+            return;
+        }
+
+        // Fails if there was another notify in between, and then there's nothing for it but to wake
+        // them all
+        if unsafe { futex_cmp_requeue(&self.seq, 1, i32::MAX, mutex, seq) } == -1 {
+            unsafe {
+                futex_wake(&self.seq, i32::MAX);
+            }
+        }
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
+
+// A counting semaphore. Waking only when the count goes up from 0 isn't enough: with two sleepers and
+// two ups in a row, the second up sees 1 and wakes nobody, leaving a sleeper behind with the count
+// above 0. So the semaphore keeps count of who's about to sleep or sleeping, and every up wakes one
+// of them if there are any.
+pub struct Sem {
+    count: AtomicU32,
+    waiters: AtomicU32,
+}
+
+unsafe impl ProcessShared for Sem {}
+
+impl Sem {
+    pub const fn new(init: u32) -> Self {
+        Sem {
+            count: AtomicU32::new(init),
+            waiters: AtomicU32::new(0),
+        }
+    }
+
+    // Decrement, sleeping until the count is above 0 if it isn't
+    pub fn down(&self) {
+        while !self.try_down() {
+            self.sleep(None);
+        }
+    }
+
+    // Decrement if that can be done without waiting. Returns whether it was.
+    pub fn try_down(&self) -> bool {
+        let mut val = self.count.load(Ordering::Acquire);
+        while val > 0 {
+            match self
+                .count
+                .compare_exchange(val, val - 1, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return true,
+                Err(actual) => val = actual,
+            }
+        }
+        false
+    }
+
+    pub fn down_timeout(&self, timeout: Duration) -> bool {
+        // A deadline too far off to be an Instant at all is as good as none
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.down_deadline(deadline),
+            None => {
+                self.down();
+                true
+            }
+        }
+    }
+
+    // Like down, but give up once the deadline has passed. Returns whether it got decremented.
+    pub fn down_deadline(&self, deadline: Instant) -> bool {
+        // The futex takes an absolute time, so this stays right however often we have to go back to
+        // sleep
+        let deadline = monotonic_timespec(deadline);
+        while !self.try_down() {
+            if !self.sleep(Some(&deadline)) {
+                // Out of time, though an up might have come in at the last moment
+                return self.try_down();
+            }
+        }
+        true
+    }
+
+    // Sleep while the count is 0. Returns false if the deadline passed first.
+    fn sleep(&self, deadline: Option<&libc::timespec>) -> bool {
+        // Registering before checking the count, which the futex wait does, and up doing it the other
+        // way around means that either we see its increment and don't sleep, or it sees us and wakes
+        // someone. Both need to be SeqCst for that.
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        let ret = unsafe {
+            match deadline {
+                Some(deadline) => futex_wait_until(&self.count, 0, deadline),
+                None => futex_wait(&self.count, 0),
+            }
+        };
+        let err = Error::last_os_error();
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+
+        if ret == -1 {
+            match err.raw_os_error() {
+                Some(libc::ETIMEDOUT) => return false,
+
+                // It wasn't 0 anymore by the time we got to sleep, or a signal handler ran
+                Some(libc::EAGAIN) | Some(libc::EINTR) => {}
+
+                _ => panic!("futex wait failed: {}", err),
+            }
+        }
+        true
+    }
+
+    // Increment the count, and wake a sleeper to take it if there is one. If another down gets in
+    // first, the woken one just goes back to sleep.
+    pub fn up(&self) {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            unsafe {
+                futex_wake(&self.count, 1);
+            }
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        self.count.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Condvar, Mutex, Sem};
+    use crate::thread::spawn;
+    use std::io::Error;
+    use std::ptr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
+
+    #[test]
+    fn threads_take_turns() {
+        let counter = Arc::new(Mutex::new(0u64));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let counter = counter.clone();
+                spawn(move || {
+                    for _ in 0..10_000 {
+                        // Not atomic, so lost updates would show up in the total
+                        let mut value = counter.lock();
+                        *value += 1;
+                    }
+                })
+                .unwrap()
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(*counter.lock(), 80_000);
+    }
+
+    #[test]
+    fn try_lock_fails_while_locked() {
+        let mutex = Mutex::new(());
+        let guard = mutex.lock();
+        assert!(mutex.try_lock().is_none());
+        drop(guard);
+        assert!(mutex.try_lock().is_some());
+    }
+
+    #[test]
+    fn notify_all_wakes_everyone() {
+        // How many waiters have gone to sleep, and whether they may go
+        let state = Arc::new((Mutex::new((0, false)), Condvar::new()));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let state = state.clone();
+                spawn(move || {
+                    let (mutex, condvar) = &*state;
+                    let mut guard = mutex.lock();
+                    guard.0 += 1;
+                    condvar.notify_all();
+                    let guard = condvar.wait_while(guard, |(_, go)| !*go);
+                    guard.0
+                })
+                .unwrap()
+            })
+            .collect();
+
+        let (mutex, condvar) = &*state;
+        let mut guard = condvar.wait_while(mutex.lock(), |(waiting, _)| *waiting < 8);
+        guard.1 = true;
+        drop(guard);
+        condvar.notify_all();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 8);
+        }
+    }
+
+    #[test]
+    fn processes_take_turns() {
+        const ROUNDS: u64 = 10_000;
+
+        unsafe {
+            let shared = libc::mmap(
+                ptr::null_mut(),
+                4096,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_SHARED,
+                -1,
+                0,
+            );
+            assert_ne!(shared, libc::MAP_FAILED, "mmap: {}", Error::last_os_error());
+            ptr::write(shared as *mut Mutex<u64>, Mutex::new(0));
+            let counter = &*(shared as *const Mutex<u64>);
+
+            let pid = libc::fork();
+            assert_ne!(pid, -1, "fork: {}", Error::last_os_error());
+            for _ in 0..ROUNDS {
+                *counter.lock() += 1;
+            }
+            if pid == 0 {
+                libc::_exit(0);
+            }
+
+            let mut status = 0;
+            assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+            assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+            assert_eq!(*counter.lock(), 2 * ROUNDS);
+
+            libc::munmap(shared, 4096);
+        }
+    }
+
+    // A tiny xorshift generator, so every process can have its own sequence without a crate for it
+    struct Rng(u64);
+
+    impl Rng {
+        fn new(seed: u64) -> Self {
+            Rng(seed | 1)
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    // Lots of processes sleeping on the semaphore at once while others do bursts of ups. A lost wakeup
+    // leaves a sleeper behind with the count above 0, so the downs never all get done.
+    #[test]
+    fn semaphore_survives_a_crowd() {
+        const DOWNERS: u64 = 8;
+        const UPPERS: u64 = 4;
+        const ROUNDS: u64 = 2000;
+
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+        println!("seed {}", seed);
+
+        unsafe {
+            let shared = libc::mmap(
+                ptr::null_mut(),
+                4096,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_SHARED,
+                -1,
+                0,
+            );
+            assert_ne!(shared, libc::MAP_FAILED, "mmap: {}", Error::last_os_error());
+            ptr::write(shared as *mut Sem, Sem::new(0));
+            let sem = &*(shared as *const Sem);
+
+            let mut children = Vec::new();
+            for i in 0..DOWNERS + UPPERS {
+                let pid = libc::fork();
+                assert_ne!(pid, -1, "fork: {}", Error::last_os_error());
+                if pid > 0 {
+                    children.push(pid);
+                    continue;
+                }
+
+                let mut rng = Rng::new(seed.wrapping_add(i.wrapping_mul(0x9e3779b97f4a7c15)));
+                if i < DOWNERS {
+                    for _ in 0..ROUNDS {
+                        sem.down();
+                        if rng.below(4) == 0 {
+                            libc::usleep(rng.below(200) as u32);
+                        }
+                    }
+                } else {
+                    // The ups for all the downs between the uppers, in bursts with pauses in
+                    // between, so the downers keep going to sleep and getting woken in crowds
+                    let mut ups = ROUNDS * DOWNERS / UPPERS;
+                    while ups > 0 {
+                        for _ in 0..(rng.below(8) + 1).min(ups) {
+                            sem.up();
+                            ups -= 1;
+                        }
+                        libc::usleep(rng.below(200) as u32);
+                    }
+                }
+                libc::_exit(0);
+            }
+
+            // A down that never returns is the only thing that can keep a child around
+            let deadline = Instant::now() + Duration::from_secs(20);
+            while !children.is_empty() && Instant::now() < deadline {
+                children.retain(|&pid| {
+                    let mut status = 0;
+                    libc::waitpid(pid, &mut status, libc::WNOHANG) == 0
+                });
+                libc::usleep(1000);
+            }
+            for &pid in &children {
+                libc::kill(pid, libc::SIGKILL);
+                libc::waitpid(pid, ptr::null_mut(), 0);
+            }
+            assert!(
+                children.is_empty(),
+                "{} processes stuck in down with the count at {} (seed {})",
+                children.len(),
+                sem.value(),
+                seed
+            );
+            assert_eq!(sem.value(), 0);
+
+            libc::munmap(shared, 4096);
+        }
+    }
+
+    #[test]
+    fn semaphore_times_out() {
+        let sem = Sem::new(1);
+        assert!(sem.try_down());
+        assert!(!sem.try_down());
+
+        let start = Instant::now();
+        assert!(!sem.down_timeout(Duration::from_millis(50)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        sem.up();
+        assert!(sem.down_timeout(Duration::from_secs(10)));
+
+        sem.up();
+        assert!(sem.down_timeout(Duration::MAX));
+    }
+}
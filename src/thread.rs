@@ -0,0 +1,599 @@
+// Threads on top of raw clone, doing by hand what pthread_create does for us.
+//
+// The tricky part is thread-local storage. Everything that touches a thread-local (errno, malloc's
+// per-thread caches, Rust's stdout lock and thread::current) finds it relative to the thread pointer,
+// so a thread that shares its parent's thread pointer shares all of those as well. CLONE_SETTLS gives
+// the new thread its own thread pointer, and we have to build a TLS block for it to point at that
+// looks enough like the one glibc would have made.
+//
+// "Enough" means the TCB header (self pointers, dtv, stack canary) and the tid in struct pthread, which
+// glibc's mutexes and raise read through THREAD_SELF. The rest of struct pthread starts out zeroed, so
+// anything that asks glibc about the thread itself (pthread_getattr_np, cancellation, pthread keys'
+// destructors) sees a thread it never set up and shouldn't be used from these threads.
+
+use crate::futex::futex_wait;
+use crate::robust;
+use crate::stack::Stack;
+use libc::{c_char, c_int, c_void, pid_t, size_t};
+use std::alloc::{self, Layout};
+use std::any::Any;
+use std::cell::UnsafeCell;
+use std::io::{Error, Result};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+
+// The same set of flags pthread_create uses
+const FLAGS: c_int = libc::CLONE_VM
+    | libc::CLONE_FS
+    | libc::CLONE_FILES
+    | libc::CLONE_SIGHAND
+    | libc::CLONE_THREAD
+    | libc::CLONE_SYSVSEM
+    | libc::CLONE_SETTLS
+    | libc::CLONE_PARENT_SETTID
+    | libc::CLONE_CHILD_CLEARTID;
+
+const DEFAULT_STACK_SIZE: usize = 2 * 1024 * 1024;
+
+// Room for glibc's struct pthread, which is its thread control block on x86_64 and sits just below the
+// thread pointer on aarch64. It's about 2.3 KiB with glibc 2.36, so a page leaves plenty of slack.
+const TCB_SIZE: usize = 4096;
+
+// Alignment of the TLS block, at least as strict as anything glibc uses
+const TLS_ALIGN: usize = 64;
+
+extern "C" {
+    // glibc skips locking in malloc and stdio while this is set, so it has to go before a second
+    // thread exists. pthread_create clears it for the same reason.
+    static mut __libc_single_threaded: c_char;
+}
+
+pub struct JoinHandle<T> {
+    packet: Box<Packet<T>>,
+
+    // Both only get freed once the thread is done with them, see Drop
+    _stack: Stack,
+    tls: Tls,
+}
+
+// Shared between the thread and its handle
+struct Packet<T> {
+    main: UnsafeCell<Option<Box<dyn FnOnce() -> T + Send>>>,
+    result: UnsafeCell<Option<std::thread::Result<T>>>,
+}
+
+// Spawn a thread running `f`. Unlike std, dropping the handle without joining waits for the thread,
+// because its stack can't be freed before it's gone.
+pub fn spawn<F, T>(f: F) -> Result<JoinHandle<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new().spawn(f)
+}
+
+pub struct Builder {
+    stack_size: usize,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder {
+            stack_size: DEFAULT_STACK_SIZE,
+        }
+    }
+
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    pub fn spawn<F, T>(self, f: F) -> Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let packet = Box::new(Packet {
+            main: UnsafeCell::new(Some(Box::new(f))),
+            result: UnsafeCell::new(None),
+        });
+        let stack = Stack::new(self.stack_size)?;
+        let tls = Tls::new()?;
+
+        unsafe {
+            ptr::write_volatile(ptr::addr_of_mut!(__libc_single_threaded), 0);
+            tls::mark_multiple_threads(tls::thread_pointer());
+
+            // Like pthread_create, have the kernel keep the tid in struct pthread: it's set before clone
+            // returns (CLONE_PARENT_SETTID) and cleared when the thread exits (CLONE_CHILD_CLEARTID)
+            let tid = tls.tid().as_ptr() as *mut pid_t;
+            let ret = libc::clone(
+                start::<T>,
+                stack.top(),
+                FLAGS,
+                &*packet as *const Packet<T> as *mut c_void,
+                tid,
+                tls.thread_pointer as *mut c_void,
+                tid,
+            );
+            if ret == -1 {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        Ok(JoinHandle {
+            packet,
+            _stack: stack,
+            tls,
+        })
+    }
+}
+
+extern "C" fn start<T>(arg: *mut c_void) -> c_int {
+    // Like after a fork, the kernel starts the thread without a robust list
+    robust::forget_registration();
+
+    let packet = unsafe { &*(arg as *const Packet<T>) };
+    let main = unsafe { (*packet.main.get()).take() }.expect("thread started twice");
+
+    // Unwinding out of here would run into clone's assembly, so panics have to stop here
+    let result = panic::catch_unwind(AssertUnwindSafe(main));
+    unsafe {
+        *packet.result.get() = Some(result);
+    }
+
+    // Returning makes clone call exit, which only ends this thread. Note that nothing runs the
+    // destructors of thread-locals, they're leaked.
+    0
+}
+
+impl<T> JoinHandle<T> {
+    pub fn tid(&self) -> pid_t {
+        self.tls.tid().load(Ordering::Acquire) as pid_t
+    }
+
+    // Wait for the thread to finish and return what it returned, or the payload if it panicked
+    pub fn join(self) -> std::result::Result<T, Box<dyn Any + Send + 'static>> {
+        self.wait();
+        unsafe { (*self.packet.result.get()).take() }.expect("thread exited without a result")
+    }
+
+    fn wait(&self) {
+        // The kernel clears the tid and does a futex wake on it once the thread is gone
+        loop {
+            let tid = self.tls.tid().load(Ordering::Acquire);
+            if tid == 0 {
+                break;
+            }
+            unsafe {
+                futex_wait(self.tls.tid(), tid);
+            }
+        }
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        self.wait();
+    }
+}
+
+// A thread's static TLS block and thread control block, plus its dynamic thread vector (dtv) which
+// tells __tls_get_addr where each module's thread-locals are
+struct Tls {
+    block: *mut u8,
+    layout: Layout,
+    thread_pointer: usize,
+
+    // Where struct pthread's tid is, relative to the thread pointer
+    tid_offset: isize,
+}
+
+// Like the stack, it's just memory
+unsafe impl Send for Tls {}
+
+// One loaded object (the executable, libc, ...) with thread-locals, as seen from the current thread
+struct Module {
+    id: usize,
+
+    // Where its block is relative to the thread pointer
+    offset: isize,
+
+    // Initial contents: the first `init_size` bytes come from `init`, the rest up to `size` are zero
+    init: *const u8,
+    init_size: usize,
+    size: usize,
+    align: usize,
+}
+
+// Static TLS blocks sit right next to the thread pointer, anything further away was allocated
+// separately for a library loaded with dlopen. We leave those shared with the parent.
+const MAX_STATIC_OFFSET: isize = 16 * 1024 * 1024;
+
+unsafe extern "C" fn collect_module(
+    info: *mut libc::dl_phdr_info,
+    _size: size_t,
+    data: *mut c_void,
+) -> c_int {
+    let info = &*info;
+    let modules = &mut *(data as *mut Vec<Module>);
+
+    if info.dlpi_tls_modid == 0 || info.dlpi_tls_data.is_null() {
+        return 0;
+    }
+
+    let offset = info.dlpi_tls_data as isize - tls::thread_pointer() as isize;
+    if offset.abs() > MAX_STATIC_OFFSET {
+        return 0;
+    }
+
+    let phdrs = slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize);
+    if let Some(phdr) = phdrs.iter().find(|phdr| phdr.p_type == libc::PT_TLS) {
+        modules.push(Module {
+            id: info.dlpi_tls_modid,
+            offset,
+            init: (info.dlpi_addr + phdr.p_vaddr) as *const u8,
+            init_size: phdr.p_filesz as usize,
+            size: phdr.p_memsz as usize,
+            align: phdr.p_align.max(1) as usize,
+        });
+    }
+
+    0
+}
+
+// glibc doesn't export where in struct pthread the tid lives, and it has moved between versions. Our
+// own thread's struct pthread has our tid in it, so look for it there, and refuse to guess if it's
+// missing or turns up more than once.
+fn tid_offset() -> Result<isize> {
+    static OFFSET: OnceLock<Option<isize>> = OnceLock::new();
+
+    let offset = *OFFSET.get_or_init(|| {
+        let tp = tls::thread_pointer();
+        let tid = unsafe { libc::gettid() };
+        let mut found = tls::PTHREAD_RANGE
+            .step_by(std::mem::size_of::<pid_t>())
+            .filter(|&offset| unsafe { *((tp as isize + offset) as *const pid_t) } == tid);
+        match (found.next(), found.next()) {
+            (Some(offset), None) => Some(offset),
+            _ => None,
+        }
+    });
+    offset.ok_or_else(|| Error::from_raw_os_error(libc::ENOTSUP))
+}
+
+impl Tls {
+    fn new() -> Result<Self> {
+        let tid_offset = tid_offset()?;
+
+        let mut modules: Vec<Module> = Vec::new();
+        unsafe {
+            libc::dl_iterate_phdr(
+                Some(collect_module),
+                &mut modules as *mut Vec<Module> as *mut c_void,
+            );
+        }
+
+        // Keep the thread pointer at the same alignment as ours so every block stays aligned
+        let align = modules
+            .iter()
+            .map(|module| module.align)
+            .fold(TLS_ALIGN, usize::max);
+        let (below, above) = tls::extent(&modules);
+        let below = (below + align - 1) & !(align - 1);
+        let layout = Layout::from_size_align(below + above + align, align)
+            .map_err(|_| Error::from_raw_os_error(libc::EINVAL))?;
+
+        unsafe {
+            let block = alloc::alloc_zeroed(layout);
+            if block.is_null() {
+                return Err(Error::from_raw_os_error(libc::ENOMEM));
+            }
+
+            let ours = tls::thread_pointer();
+            let misalignment = (ours % align + align - (block as usize + below) % align) % align;
+            let thread_pointer = block as usize + below + misalignment;
+
+            // Fresh copies of every module's initial image, so the thread starts out with its own errno,
+            // no malloc cache, no stdout lock owner and so on
+            for module in &modules {
+                let dest = (thread_pointer as isize + module.offset) as *mut u8;
+                ptr::copy_nonoverlapping(module.init, dest, module.init_size);
+                ptr::write_bytes(
+                    dest.add(module.init_size),
+                    0,
+                    module.size - module.init_size,
+                );
+            }
+
+            let dtv = copy_dtv(tls::dtv(ours), thread_pointer, &modules)?;
+            tls::init_tcb(ours, thread_pointer, dtv);
+
+            Ok(Tls {
+                block,
+                layout,
+                thread_pointer,
+                tid_offset,
+            })
+        }
+    }
+
+    fn tid(&self) -> &AtomicU32 {
+        unsafe { &*((self.thread_pointer as isize + self.tid_offset) as *const AtomicU32) }
+    }
+}
+
+impl Drop for Tls {
+    fn drop(&mut self) {
+        unsafe {
+            // glibc may have reallocated the dtv in the meantime, so free whatever is there now
+            let dtv = tls::dtv(self.thread_pointer);
+            libc::free(dtv.sub(1) as *mut c_void);
+
+            alloc::dealloc(self.block, self.layout);
+        }
+    }
+}
+
+// A dtv entry is a union of a counter and a pointer pair
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DtvEntry {
+    val: usize,
+    to_free: usize,
+}
+
+// Make a copy of a dtv pointing at the new blocks. The pointer glibc keeps in the TCB is to the second
+// entry: the one before it holds the number of module slots, and the one it points to holds the
+// generation. It has to be malloc'd since glibc will realloc it if a library is loaded later.
+unsafe fn copy_dtv(
+    ours: *const DtvEntry,
+    thread_pointer: usize,
+    modules: &[Module],
+) -> Result<*mut DtvEntry> {
+    let slots = (*ours.sub(1)).val;
+    let entries = libc::calloc(slots + 2, std::mem::size_of::<DtvEntry>()) as *mut DtvEntry;
+    if entries.is_null() {
+        return Err(Error::from_raw_os_error(libc::ENOMEM));
+    }
+    ptr::copy_nonoverlapping(ours.sub(1), entries, slots + 2);
+
+    let dtv = entries.add(1);
+    for module in modules.iter().filter(|module| module.id <= slots) {
+        *dtv.add(module.id) = DtvEntry {
+            val: (thread_pointer as isize + module.offset) as usize,
+            to_free: 0,
+        };
+    }
+
+    Ok(dtv)
+}
+
+// x86_64 uses TLS "variant II": the blocks are below the thread pointer, which points at the TCB
+#[cfg(target_arch = "x86_64")]
+mod tls {
+    use super::{DtvEntry, Module, TCB_SIZE};
+    use std::arch::asm;
+    use std::ops::Range;
+    use std::ptr;
+
+    // Offsets into tcbhead_t
+    const TCB_SELF: usize = 0;
+    const TCB_DTV: usize = 8;
+    const TCB_SELF_AGAIN: usize = 16;
+    const TCB_MULTIPLE_THREADS: usize = 24;
+
+    // Up to and including the stack protector canary and pointer guard
+    const TCB_HEADER_SIZE: usize = 56;
+
+    // struct pthread starts with tcbhead_t at the thread pointer, the rest of it follows
+    pub(super) const PTHREAD_RANGE: Range<isize> = TCB_HEADER_SIZE as isize..TCB_SIZE as isize;
+
+    pub(super) fn thread_pointer() -> usize {
+        let tp: usize;
+        unsafe {
+            asm!("mov {}, fs:0", out(reg) tp);
+        }
+        tp
+    }
+
+    // How far the blocks reach below and above the thread pointer
+    pub(super) fn extent(modules: &[Module]) -> (usize, usize) {
+        let below = modules
+            .iter()
+            .map(|module| (-module.offset) as usize)
+            .max()
+            .unwrap_or(0);
+        (below, TCB_SIZE)
+    }
+
+    pub(super) unsafe fn dtv(tp: usize) -> *mut DtvEntry {
+        *((tp + TCB_DTV) as *const *mut DtvEntry)
+    }
+
+    pub(super) unsafe fn mark_multiple_threads(tp: usize) {
+        ptr::write_volatile((tp + TCB_MULTIPLE_THREADS) as *mut i32, 1);
+    }
+
+    pub(super) unsafe fn init_tcb(ours: usize, tp: usize, dtv: *mut DtvEntry) {
+        // The canary and pointer guard have to match ours, everything else in struct pthread can start
+        // out zeroed
+        ptr::copy_nonoverlapping(ours as *const u8, tp as *mut u8, TCB_HEADER_SIZE);
+        *((tp + TCB_SELF) as *mut usize) = tp;
+        *((tp + TCB_DTV) as *mut *mut DtvEntry) = dtv;
+        *((tp + TCB_SELF_AGAIN) as *mut usize) = tp;
+        mark_multiple_threads(tp);
+    }
+}
+
+// aarch64 uses TLS "variant I": the thread pointer points at a two-word header (dtv and a private
+// word), the blocks come after it and struct pthread sits right before it
+#[cfg(target_arch = "aarch64")]
+mod tls {
+    use super::{DtvEntry, Module, TCB_SIZE};
+    use std::arch::asm;
+    use std::ops::Range;
+    use std::ptr;
+
+    const TCB_DTV: usize = 0;
+    const TCB_HEADER_SIZE: usize = 16;
+
+    // All of struct pthread is below the thread pointer
+    pub(super) const PTHREAD_RANGE: Range<isize> = -(TCB_SIZE as isize)..0;
+
+    pub(super) fn thread_pointer() -> usize {
+        let tp: usize;
+        unsafe {
+            asm!("mrs {}, tpidr_el0", out(reg) tp);
+        }
+        tp
+    }
+
+    pub(super) fn extent(modules: &[Module]) -> (usize, usize) {
+        let above = modules
+            .iter()
+            .map(|module| module.offset as usize + module.size)
+            .fold(TCB_HEADER_SIZE, usize::max);
+        (TCB_SIZE, above)
+    }
+
+    pub(super) unsafe fn dtv(tp: usize) -> *mut DtvEntry {
+        *((tp + TCB_DTV) as *const *mut DtvEntry)
+    }
+
+    // glibc keeps track of this in a global on aarch64
+    pub(super) unsafe fn mark_multiple_threads(_tp: usize) {}
+
+    pub(super) unsafe fn init_tcb(ours: usize, tp: usize, dtv: *mut DtvEntry) {
+        ptr::copy_nonoverlapping(ours as *const u8, tp as *mut u8, TCB_HEADER_SIZE);
+        *((tp + TCB_DTV) as *mut *mut DtvEntry) = dtv;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::spawn;
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn returns_values() {
+        let handles: Vec<_> = (0..500u64)
+            .map(|i| spawn(move || i * i).expect("could not spawn thread"))
+            .collect();
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            let i = i as u64;
+            assert_eq!(handle.join().unwrap(), i * i);
+        }
+    }
+
+    #[test]
+    fn threads_allocate_concurrently() {
+        let handles: Vec<_> = (0..200)
+            .map(|i| {
+                spawn(move || {
+                    let mut words = Vec::new();
+                    for j in 0..100 {
+                        words.push(format!("{}-{}", i, j));
+                    }
+                    words.join(",")
+                })
+                .expect("could not spawn thread")
+            })
+            .collect();
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            let joined = handle.join().unwrap();
+            assert_eq!(joined.split(',').count(), 100);
+            assert!(joined.starts_with(&format!("{}-0,", i)));
+        }
+    }
+
+    #[test]
+    fn threads_share_memory() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..300)
+            .map(|_| {
+                let counter = counter.clone();
+                spawn(move || counter.fetch_add(1, Ordering::SeqCst)).unwrap()
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(counter.load(Ordering::SeqCst), 300);
+    }
+
+    #[test]
+    fn thread_locals_are_per_thread() {
+        thread_local! {
+            static VALUE: Cell<u32> = const { Cell::new(1) };
+        }
+
+        VALUE.with(|value| value.set(2));
+
+        let handle = spawn(|| {
+            let before = VALUE.with(|value| value.get());
+            VALUE.with(|value| value.set(3));
+            (before, unsafe { libc::gettid() })
+        })
+        .unwrap();
+        let tid = handle.tid();
+        let (before, their_tid) = handle.join().unwrap();
+
+        assert_eq!(before, 1);
+        assert_eq!(VALUE.with(|value| value.get()), 2);
+        assert_eq!(tid, their_tid);
+        assert_ne!(their_tid, unsafe { libc::gettid() });
+    }
+
+    #[test]
+    fn pthread_mutexes_know_the_owner() {
+        // Error-checking mutexes record the owner's tid from struct pthread, so with a zero tid the
+        // first lock would already look like a relock
+        let handle = spawn(|| unsafe {
+            let mut attr: libc::pthread_mutexattr_t = std::mem::zeroed();
+            libc::pthread_mutexattr_init(&mut attr);
+            libc::pthread_mutexattr_settype(&mut attr, libc::PTHREAD_MUTEX_ERRORCHECK);
+            let mut mutex: libc::pthread_mutex_t = std::mem::zeroed();
+            libc::pthread_mutex_init(&mut mutex, &attr);
+
+            let first = libc::pthread_mutex_lock(&mut mutex);
+            let again = libc::pthread_mutex_lock(&mut mutex);
+            let unlock = libc::pthread_mutex_unlock(&mut mutex);
+            libc::pthread_mutex_destroy(&mut mutex);
+            (first, again, unlock)
+        })
+        .unwrap();
+        assert_eq!(handle.join().unwrap(), (0, libc::EDEADLK, 0));
+    }
+
+    #[test]
+    fn panics_are_returned() {
+        let handle = spawn(|| -> u32 { panic!("oh no") }).unwrap();
+        let payload = handle.join().unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"oh no"));
+    }
+
+    #[test]
+    fn threads_spawn_threads() {
+        let handle = spawn(|| {
+            let inner: Vec<_> = (0..10u32).map(|i| spawn(move || i + 1).unwrap()).collect();
+            inner.into_iter().map(|h| h.join().unwrap()).sum::<u32>()
+        })
+        .unwrap();
+        assert_eq!(handle.join().unwrap(), 55);
+    }
+}
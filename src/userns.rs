@@ -0,0 +1,450 @@
+// Setting up the uid and gid maps of a user namespace from the outside, the way newuidmap and
+// newgidmap do it
+
+use libc::{pid_t, uid_t};
+use std::ffi::CStr;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::process::Command;
+use std::ptr;
+
+// The kernel refuses maps with more lines than this
+const MAX_LINES: usize = 340;
+
+const CAP_SETGID: u32 = 6;
+const CAP_SETUID: u32 = 7;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    Uid,
+    Gid,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Uid => "uid",
+            Kind::Gid => "gid",
+        }
+    }
+
+    // Where shadow-utils keeps the ranges each user may map
+    fn subid_file(self) -> &'static str {
+        match self {
+            Kind::Uid => "/etc/subuid",
+            Kind::Gid => "/etc/subgid",
+        }
+    }
+
+    // The capability that allows writing arbitrary maps of this kind
+    fn capability(self) -> u32 {
+        match self {
+            Kind::Uid => CAP_SETUID,
+            Kind::Gid => CAP_SETGID,
+        }
+    }
+
+    fn current(self) -> u32 {
+        unsafe {
+            match self {
+                Kind::Uid => libc::geteuid(),
+                Kind::Gid => libc::getegid(),
+            }
+        }
+    }
+}
+
+// One line of a map: `count` ids starting at `inside` in the namespace are `outside` outside of it
+#[derive(Clone, Copy, Debug)]
+pub struct Range {
+    pub inside: u32,
+    pub outside: u32,
+    pub count: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct IdMap {
+    pub kind: Kind,
+    pub ranges: Vec<Range>,
+}
+
+impl IdMap {
+    // Map root in the namespace to our own id, which is all an unprivileged process may do
+    pub fn own(kind: Kind) -> Self {
+        IdMap {
+            kind,
+            ranges: vec![Range {
+                inside: 0,
+                outside: kind.current(),
+                count: 1,
+            }],
+        }
+    }
+
+    // Add the subordinate ids our user has been given, mapped one after the other right after the
+    // ones already in the map
+    pub fn with_subids(self) -> Result<Self> {
+        let ranges = subid_ranges(self.kind)?;
+        self.with_ranges(ranges)
+    }
+
+    // Add `(outside, count)` ranges, mapped one after the other right after the ones already in the
+    // map
+    fn with_ranges(mut self, ranges: Vec<(u32, u32)>) -> Result<Self> {
+        let overflow = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "the ranges in {} don't fit in 32-bit ids",
+                    self.kind.subid_file()
+                ),
+            )
+        };
+
+        let mut next = 0;
+        for range in &self.ranges {
+            next = next.max(range.inside.checked_add(range.count).ok_or_else(overflow)?);
+        }
+
+        for (outside, count) in ranges {
+            self.ranges.push(Range {
+                inside: next,
+                outside,
+                count,
+            });
+            next = next.checked_add(count).ok_or_else(overflow)?;
+        }
+
+        Ok(self)
+    }
+
+    // Check the rules the kernel will check, so we can say what's wrong instead of just getting EPERM
+    // or EINVAL back
+    pub fn validate(&self) -> Result<()> {
+        let name = self.kind.name();
+
+        if self.ranges.is_empty() {
+            return Err(invalid(format!("the {} map is empty", name)));
+        }
+        if self.ranges.len() > MAX_LINES {
+            return Err(invalid(format!(
+                "the {} map has {} lines, the kernel allows at most {}",
+                name,
+                self.ranges.len(),
+                MAX_LINES
+            )));
+        }
+
+        for (i, a) in self.ranges.iter().enumerate() {
+            if a.count == 0 {
+                return Err(invalid(format!("{} map line {} maps no ids", name, i + 1)));
+            }
+            if a.inside.checked_add(a.count).is_none() || a.outside.checked_add(a.count).is_none() {
+                return Err(invalid(format!("{} map line {} overflows", name, i + 1)));
+            }
+
+            for (j, b) in self.ranges.iter().enumerate().skip(i + 1) {
+                if overlaps(a.inside, a.count, b.inside, b.count) {
+                    return Err(invalid(format!(
+                        "{} map lines {} and {} overlap inside the namespace",
+                        name,
+                        i + 1,
+                        j + 1
+                    )));
+                }
+                if overlaps(a.outside, a.count, b.outside, b.count) {
+                    return Err(invalid(format!(
+                        "{} map lines {} and {} overlap outside the namespace",
+                        name,
+                        i + 1,
+                        j + 1
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Without the capability, the only thing we can map ourselves is our own id. Anything more, like
+    // the subordinate ids, has to go through the setuid new{uid,gid}map, which checks the ranges
+    // against /etc/subuid or /etc/subgid.
+    fn needs_helper(&self) -> Result<bool> {
+        let only_own = self.ranges.len() == 1
+            && self.ranges[0].count == 1
+            && self.ranges[0].outside == self.kind.current();
+        Ok(!only_own && !has_capability(self.kind.capability())?)
+    }
+
+    fn write_with_helper(&self, pid: pid_t) -> Result<()> {
+        let helper = format!("new{}map", self.kind.name());
+        let mut command = Command::new(&helper);
+        command.arg(pid.to_string());
+        for range in &self.ranges {
+            command.args([
+                range.inside.to_string(),
+                range.outside.to_string(),
+                range.count.to_string(),
+            ]);
+        }
+
+        let status = command
+            .status()
+            .map_err(|err| Error::new(err.kind(), format!("running {}: {}", helper, err)))?;
+        if !status.success() {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "{} {}, are the ranges in {} yours?",
+                    helper,
+                    status,
+                    self.kind.subid_file()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // Install the map for a process that has just created a user namespace. For a gid map without
+    // CAP_SETGID, setgroups has to be denied first, otherwise the process could drop supplementary
+    // groups it was being kept out of something with.
+    pub fn write(&self, pid: pid_t) -> Result<()> {
+        self.validate()?;
+        if self.needs_helper()? {
+            // It takes care of setgroups itself
+            return self.write_with_helper(pid);
+        }
+
+        let name = self.kind.name();
+
+        if self.kind == Kind::Gid && !has_capability(CAP_SETGID)? {
+            fs::write(format!("/proc/{}/setgroups", pid), "deny")
+                .map_err(|err| explain(err, "writing setgroups"))?;
+        }
+
+        fs::write(format!("/proc/{}/{}_map", pid, name), self.to_string())
+            .map_err(|err| explain(err, &format!("writing the {} map", name)))
+    }
+}
+
+impl fmt::Display for IdMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for range in &self.ranges {
+            writeln!(f, "{} {} {}", range.inside, range.outside, range.count)?;
+        }
+        Ok(())
+    }
+}
+
+fn overlaps(a: u32, a_count: u32, b: u32, b_count: u32) -> bool {
+    (a as u64) < b as u64 + b_count as u64 && (b as u64) < a as u64 + a_count as u64
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+// The kernel says EPERM for a handful of different reasons, try to say which one it probably was
+fn explain(err: Error, what: &str) -> Error {
+    let why = match err.raw_os_error() {
+        Some(libc::EPERM) => {
+            "the map was already written (each map can only be written once), or we aren't in the \
+             parent user namespace of the child, or setgroups wasn't denied before an unprivileged \
+             gid map"
+        }
+        Some(libc::EINVAL) => "the kernel didn't like the format of the map",
+        Some(libc::ENOENT) | Some(libc::ESRCH) => "the child is gone",
+        _ => return err,
+    };
+    Error::new(err.kind(), format!("{}: {} ({})", what, why, err))
+}
+
+// Whether we have a capability in our own user namespace
+fn has_capability(cap: u32) -> Result<bool> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    let effective = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .ok_or_else(|| invalid("could not find CapEff in /proc/self/status".to_string()))?;
+    Ok(effective & (1 << cap) != 0)
+}
+
+fn user_name(uid: uid_t) -> Option<String> {
+    unsafe {
+        let mut pwd: libc::passwd = mem::zeroed();
+        let mut buf = [0 as libc::c_char; 1024];
+        let mut result = ptr::null_mut();
+        libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result);
+        if result.is_null() {
+            return None;
+        }
+        Some(CStr::from_ptr(pwd.pw_name).to_string_lossy().into_owned())
+    }
+}
+
+// The ranges from /etc/subuid or /etc/subgid that belong to us. Both files are keyed by user, even
+// /etc/subgid. The user is the one our own map entry is for, so the two can't disagree when we're
+// running setuid.
+pub fn subid_ranges(kind: Kind) -> Result<Vec<(u32, u32)>> {
+    let uid = Kind::Uid.current();
+    let contents = fs::read_to_string(kind.subid_file())
+        .map_err(|err| Error::new(err.kind(), format!("{}: {}", kind.subid_file(), err)))?;
+    Ok(parse_subids(&contents, user_name(uid).as_deref(), uid))
+}
+
+// Lines look like `name:start:count`, where the name can also be a numeric uid. Anything else is
+// skipped, like shadow-utils does.
+fn parse_subids(contents: &str, name: Option<&str>, uid: uid_t) -> Vec<(u32, u32)> {
+    let mut ranges = Vec::new();
+    for line in contents.lines() {
+        let mut fields = line.trim().split(':');
+        let (Some(owner), Some(start), Some(count)) = (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+
+        if Some(owner) != name && owner != uid.to_string() {
+            continue;
+        }
+        if let (Ok(start), Ok(count)) = (start.parse(), count.parse()) {
+            ranges.push((start, count));
+        }
+    }
+
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_subids, IdMap, Kind, Range, MAX_LINES};
+
+    fn map(ranges: &[(u32, u32, u32)]) -> IdMap {
+        IdMap {
+            kind: Kind::Uid,
+            ranges: ranges
+                .iter()
+                .map(|&(inside, outside, count)| Range {
+                    inside,
+                    outside,
+                    count,
+                })
+                .collect(),
+        }
+    }
+
+    fn error(map: IdMap) -> String {
+        map.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn valid_maps_pass() {
+        map(&[(0, 1000, 1)]).validate().unwrap();
+        map(&[(0, 1000, 1), (1, 100000, 65536)]).validate().unwrap();
+        // Ranges that only touch don't overlap
+        map(&[(0, 100, 10), (10, 110, 10)]).validate().unwrap();
+        // Ending exactly at the top of the id space is fine
+        map(&[(0, u32::MAX - 10, 10)]).validate().unwrap();
+    }
+
+    #[test]
+    fn overlapping_maps_fail() {
+        assert_eq!(
+            error(map(&[(0, 1000, 10), (5, 2000, 10)])),
+            "uid map lines 1 and 2 overlap inside the namespace"
+        );
+        assert_eq!(
+            error(map(&[(0, 1000, 10), (10, 1009, 1)])),
+            "uid map lines 1 and 2 overlap outside the namespace"
+        );
+        assert_eq!(
+            error(map(&[(0, 1000, 1), (1, 2000, 1), (100, 2000, 1)])),
+            "uid map lines 2 and 3 overlap outside the namespace"
+        );
+    }
+
+    #[test]
+    fn zero_counts_fail() {
+        assert_eq!(
+            error(map(&[(0, 1000, 1), (1, 2000, 0)])),
+            "uid map line 2 maps no ids"
+        );
+    }
+
+    #[test]
+    fn overflowing_maps_fail() {
+        assert_eq!(
+            error(map(&[(u32::MAX, 1000, 2)])),
+            "uid map line 1 overflows"
+        );
+        assert_eq!(
+            error(map(&[(0, 1000, 1), (1, u32::MAX - 1, 2)])),
+            "uid map line 2 overflows"
+        );
+    }
+
+    #[test]
+    fn map_size_is_limited() {
+        assert_eq!(error(map(&[])), "the uid map is empty");
+
+        let lines: Vec<_> = (0..MAX_LINES as u32).map(|i| (i, 1000 + i, 1)).collect();
+        map(&lines).validate().unwrap();
+
+        let lines: Vec<_> = (0..MAX_LINES as u32 + 1)
+            .map(|i| (i, 1000 + i, 1))
+            .collect();
+        assert_eq!(
+            error(map(&lines)),
+            format!(
+                "the uid map has {} lines, the kernel allows at most {}",
+                MAX_LINES + 1,
+                MAX_LINES
+            )
+        );
+    }
+
+    #[test]
+    fn subids_follow_the_map() {
+        let map = map(&[(0, 1000, 1)])
+            .with_ranges(vec![(100000, 65536), (300000, 10)])
+            .unwrap();
+        let ranges: Vec<_> = map
+            .ranges
+            .iter()
+            .map(|range| (range.inside, range.outside, range.count))
+            .collect();
+        assert_eq!(
+            ranges,
+            [(0, 1000, 1), (1, 100000, 65536), (65537, 300000, 10)]
+        );
+
+        assert!(IdMap::own(Kind::Uid)
+            .with_ranges(vec![(100000, u32::MAX)])
+            .is_err());
+    }
+
+    #[test]
+    fn subid_lines_are_parsed() {
+        let contents = "\
+            alice:100000:65536\n\
+            bob:165536:65536\n\
+            1000:300000:10\n\
+            \n\
+            alice:400000\n\
+            alice:x:10\n\
+            alice:500000:-1\n\
+            # a comment\n\
+            \x20 alice:600000:5 \n";
+
+        assert_eq!(
+            parse_subids(contents, Some("alice"), 1000),
+            [(100000, 65536), (300000, 10), (600000, 5)]
+        );
+        // Numeric owners still match without a name in /etc/passwd
+        assert_eq!(parse_subids(contents, None, 1000), [(300000, 10)]);
+        assert_eq!(parse_subids(contents, Some("bob"), 1001), [(165536, 65536)]);
+        assert_eq!(parse_subids(contents, Some("carol"), 1002), []);
+    }
+}